@@ -0,0 +1,270 @@
+use std::{
+    fs::{File, OpenOptions},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    conflicts::{find_conflicts, read_conflicts, write_conflicts, Conflict},
+    env::{link_environment, LinkMode},
+};
+
+/// An assembled environment, reused by every run of the same closure. It is
+/// locked shared for as long as it's in use, which keeps it from being
+/// deleted by another run.
+pub struct CachedEnvironment {
+    /// The cache entry, which the environment is mounted or linked under.
+    pub dir: PathBuf,
+    /// Whether the layers are stacked with overlayfs, instead of being
+    /// linked into `dir/root`.
+    pub stacked: bool,
+    /// The files that packages of the environment conflict on.
+    pub conflicts: Vec<Conflict>,
+    _lock: Flock<File>,
+}
+
+/// Gets the text describing the environment of layers, which is hashed into
+/// the name of its cache entry. It includes the inode and modification time
+/// of each layer, which change when a package is reinstalled or when `base`
+/// is rebuilt.
+fn describe_layers(layers: &[PathBuf], mode: LinkMode) -> Result<String> {
+    let mut description = format!("{:?}\n", mode);
+    for layer in layers {
+        let meta = layer.symlink_metadata().context(anyhow!(
+            "Failed to read metadata of {}",
+            layer.display()
+        ))?;
+        description.push_str(&format!(
+            "{} {} {} {}\n",
+            layer.display(),
+            meta.ino(),
+            meta.mtime(),
+            meta.mtime_nsec()
+        ));
+    }
+    Ok(description)
+}
+
+/// Hashes the description of an environment into the name of its entry. The
+/// hash has to be the same across builds of dpt, or every entry would be
+/// assembled again after an update.
+fn hash_description(description: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(description));
+    hash[..32].to_string()
+}
+
+/// Checks whether the entry named key still describes what is in the store.
+fn entry_is_valid(cache: &Path, key: &str) -> bool {
+    let Ok(layers) = std::fs::read_to_string(cache.join(key).join("layers"))
+    else {
+        return false;
+    };
+    let mut lines = layers.lines();
+    let mode = match lines.next() {
+        Some("Hardlink") => LinkMode::Hardlink,
+        Some("Symlink") => LinkMode::Symlink,
+        _ => return false,
+    };
+    let layers = lines.map(PathBuf::from).collect::<Vec<PathBuf>>();
+    match describe_layers(&layers, mode) {
+        Ok(x) => hash_description(&x) == key,
+        Err(_) => false,
+    }
+}
+
+/// Locks the file at path, retrying when it gets deleted by another process
+/// while waiting for the lock.
+fn lock_file(path: &Path, arg: FlockArg) -> Result<Option<Flock<File>>> {
+    loop {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .context(anyhow!("Failed to open lock {}", path.display()))?;
+        let lock = match Flock::lock(file, arg) {
+            Ok(x) => x,
+            Err((_, Errno::EWOULDBLOCK)) => return Ok(None),
+            Err((_, e)) => return Err(anyhow!(e)),
+        };
+        match path.metadata() {
+            Ok(x) if x.ino() == lock.metadata()?.ino() => {
+                return Ok(Some(lock))
+            }
+            _ => continue,
+        }
+    }
+}
+
+fn remove_entry(cache: &Path, key: &str) -> Result<()> {
+    let dir = cache.join(key);
+    if dir.symlink_metadata().is_ok() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    std::fs::remove_file(cache.join(format!("{}.lock", key)))?;
+    Ok(())
+}
+
+/// How long an entry is kept without any run using it. Entries are only
+/// used by the runs of one closure, so they'd pile up otherwise.
+const UNUSED_ENTRY_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Checks whether no run used the entry locked by the file at path for
+/// longer than [`UNUSED_ENTRY_LIFETIME`]. Runs set the modification time of
+/// the lock when they use the entry.
+fn entry_is_unused(path: &Path) -> bool {
+    path.metadata()
+        .and_then(|x| x.modified())
+        .ok()
+        .and_then(|x| x.elapsed().ok())
+        .is_some_and(|x| x > UNUSED_ENTRY_LIFETIME)
+}
+
+/// Deletes the entries which aren't in use and either no longer match the
+/// store or haven't been used for a while.
+fn remove_stale_entries(cache: &Path, current: &str) -> Result<()> {
+    for ent in std::fs::read_dir(cache)? {
+        let path = ent?.path();
+        let Some(key) = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.strip_suffix(".lock"))
+        else {
+            continue;
+        };
+        if key == current {
+            continue;
+        }
+        if let Some(_lock) = lock_file(&path, FlockArg::LockExclusiveNonblock)?
+        {
+            if entry_is_unused(&path) || !entry_is_valid(cache, key) {
+                remove_entry(cache, key)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Gets the environment made of layers from the cache in run_location,
+/// assembling it when there is none yet. can_stack is called with the
+/// directory of a new entry to decide whether to use overlayfs or to link the
+/// files into it.
+pub fn get_cached_environment(
+    run_location: &Path,
+    layers: &[PathBuf],
+    mode: LinkMode,
+    can_stack: impl FnOnce(&Path) -> bool,
+) -> Result<CachedEnvironment> {
+    let cache = run_location.join("cache");
+    std::fs::DirBuilder::new().recursive(true).create(&cache)?;
+
+    let description = describe_layers(layers, mode)?;
+    let key = hash_description(&description);
+    let dir = cache.join(&key);
+
+    let lock = lock_file(
+        &cache.join(format!("{}.lock", key)),
+        FlockArg::LockExclusive,
+    )?
+    .ok_or(anyhow!("Failed to lock environment {}", key))?;
+    lock.set_modified(SystemTime::now())?;
+
+    // The list of layers is written last, so without it the entry is from
+    // a run that didn't finish assembling it.
+    if !dir.join("layers").is_file() {
+        if dir.symlink_metadata().is_ok() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        std::fs::DirBuilder::new().recursive(true).create(&dir)?;
+        let conflicts = find_conflicts(layers)?;
+        for conflict in &conflicts {
+            warn!("{}", conflict);
+        }
+        write_conflicts(&dir.join("conflicts"), &conflicts)?;
+        if can_stack(&dir) {
+            std::fs::write(dir.join("stacked"), "")?;
+        } else {
+            link_environment(layers, &dir.join("root"), mode)?;
+        }
+        let mut list = format!("{:?}\n", mode);
+        for layer in layers {
+            list.push_str(&format!("{}\n", layer.display()));
+        }
+        std::fs::write(dir.join("layers"), list)?;
+    }
+    let stacked = dir.join("stacked").is_file();
+    let conflicts = match dir.join("conflicts").is_file() {
+        true => read_conflicts(&dir.join("conflicts"))?,
+        false => find_conflicts(layers)?,
+    };
+    lock.relock(FlockArg::LockShared)?;
+
+    if let Err(e) = remove_stale_entries(&cache, &key) {
+        warn!("Failed to clean up the environment cache: {}", e);
+    }
+
+    Ok(CachedEnvironment {
+        dir,
+        stacked,
+        conflicts,
+        _lock: lock,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unused_entries_are_removed() {
+        let dir = std::env::temp_dir()
+            .join(format!("dpt-cache-unused-{}", std::process::id()));
+        let cache = dir.join("cache");
+        std::fs::create_dir_all(dir.join("layer")).unwrap();
+        std::fs::create_dir_all(&cache).unwrap();
+        let layers = [dir.join("layer")];
+        let key = hash_description(
+            &describe_layers(&layers, LinkMode::Symlink).unwrap(),
+        );
+        std::fs::create_dir(cache.join(&key)).unwrap();
+        std::fs::write(
+            cache.join(&key).join("layers"),
+            format!("Symlink\n{}\n", layers[0].display()),
+        )
+        .unwrap();
+        let lock = cache.join(format!("{}.lock", key));
+        std::fs::write(&lock, "").unwrap();
+
+        remove_stale_entries(&cache, "other").unwrap();
+        assert!(entry_is_valid(&cache, &key));
+
+        File::options()
+            .write(true)
+            .open(&lock)
+            .unwrap()
+            .set_modified(SystemTime::now() - UNUSED_ENTRY_LIFETIME * 2)
+            .unwrap();
+        remove_stale_entries(&cache, "other").unwrap();
+        assert!(!cache.join(&key).exists());
+        assert!(!lock.exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keys_are_stable() {
+        assert_eq!(
+            hash_description("Hardlink\n/dpt/base 1 2 3\n"),
+            "3926b523b85007852b85fd6880be6f09"
+        );
+    }
+}
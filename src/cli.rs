@@ -0,0 +1,393 @@
+use std::{num::NonZeroUsize, path::PathBuf};
+
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use clap_complete::engine::{ArgValueCandidates, CompletionCandidate};
+
+use crate::{
+    index::GenIndexOptions, pkg::string_to_package, run::RunOptions,
+    store::get_store_location,
+};
+
+/// Dpt, package management, done right.
+#[derive(Parser, Debug)]
+#[command(name = "dpt", version, disable_version_flag = true)]
+pub struct Cli {
+    /// Print version
+    #[arg(short, long, action = ArgAction::Version)]
+    version: Option<bool>,
+    /// Prints JSON instead of text, for the commands that print results
+    #[arg(long, global = true)]
+    pub json: bool,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Rebuilds the system according to the dpt file
+    Rebuild {
+        /// Only prints the packages that would be installed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Adds packages to the dpt file
+    Add {
+        /// Runs `dpt rebuild` afterwards
+        #[arg(short, long)]
+        rebuild: bool,
+        /// Names of packages, or `name-version` to pin a version
+        #[arg(required = true)]
+        packages: Vec<String>,
+    },
+    /// Removes packages from the dpt file
+    Remove {
+        /// Runs `dpt rebuild` afterwards
+        #[arg(short, long)]
+        rebuild: bool,
+        #[arg(required = true, add = installed_packages())]
+        packages: Vec<String>,
+    },
+    /// Lists the packages in the store
+    List {
+        #[command(flatten)]
+        filter: ListFilter,
+    },
+    /// Shows the dependency paths from the packages of the dpt file to a
+    /// package
+    Why {
+        #[arg(add = installed_packages())]
+        package: String,
+    },
+    /// Lists the packages depending on a package, directly or through others
+    Rdepends {
+        /// Looks at every package of the repositories, instead of the locked
+        /// ones
+        #[arg(long)]
+        all: bool,
+        #[arg(add = installed_packages())]
+        package: String,
+    },
+    /// Prints the dependency graph of a package, or of the locked packages, in
+    /// the DOT language of Graphviz
+    Graph {
+        /// Resolves the dependencies of this package in the repositories
+        package: Option<String>,
+    },
+    /// Checks the packages in the store against the manifests recorded when
+    /// they were installed
+    Verify {
+        /// Only checks this package, in every version in the store unless
+        /// given as `name-version`
+        #[arg(add = installed_packages())]
+        package: Option<String>,
+        /// Reinstalls the packages that don't match their manifest
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Locks or unlocks the packages in the store
+    Store {
+        #[command(subcommand)]
+        command: StoreCommand,
+    },
+    /// Searches the repositories for packages with the term in their name
+    Search { term: String },
+    /// Shows details on a package of the repositories
+    Info {
+        /// The name of the package, or `name-version` for another version
+        /// than the newest
+        package: String,
+    },
+    /// Runs a package
+    Run {
+        #[command(flatten)]
+        options: RunArgs,
+        /// The package, followed by the arguments passed to it
+        #[arg(
+            required = true,
+            value_name = "PACKAGE",
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            add = installed_packages()
+        )]
+        command: Vec<String>,
+    },
+    /// Runs the first package in an environment that also has the others
+    RunMulti {
+        #[command(flatten)]
+        cmd: CmdArgs,
+        #[command(flatten)]
+        options: RunArgs,
+        #[arg(required = true, add = installed_packages())]
+        packages: Vec<String>,
+        /// Arguments passed to the command
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Fetches packages missing from the store, and runs them like run-multi
+    DevEnv {
+        #[command(flatten)]
+        cmd: CmdArgs,
+        #[command(flatten)]
+        options: RunArgs,
+        #[arg(required = true)]
+        packages: Vec<String>,
+        /// Arguments passed to the command
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Starts your shell in an environment with the packages
+    Shell {
+        /// Fetches packages missing from the store, like dev-env
+        #[arg(long)]
+        dev: bool,
+        #[command(flatten)]
+        options: RunArgs,
+        #[arg(required = true, add = installed_packages())]
+        packages: Vec<String>,
+        /// Arguments passed to the shell
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Generates a package from a directory
+    GenPkg {
+        directory: PathBuf,
+        /// Defaults to the directory with the `.dpt` extension
+        output: Option<PathBuf>,
+    },
+    /// Generates the index of the package repository in the current
+    /// directory
+    GenIndex {
+        #[command(flatten)]
+        index: IndexArgs,
+    },
+    /// Manages the packages of the repository in the current directory
+    Repo {
+        #[command(subcommand)]
+        command: RepoCommand,
+        #[command(flatten)]
+        index: IndexArgs,
+    },
+    /// Prints the script that registers completions for a shell
+    Completions { shell: CompletionShell },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RepoCommand {
+    /// Copies `.dpt` files into the repository
+    Add {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Deletes a package from the repository, in every version if none is
+    /// given
+    Remove {
+        name: String,
+        version: Option<String>,
+    },
+    /// Deletes all but the newest versions of each package
+    Prune {
+        /// How many versions to keep
+        #[arg(short, long)]
+        keep: NonZeroUsize,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum StoreCommand {
+    /// Gives write access to packages back, for maintenance
+    Unlock {
+        /// Only unlocks this package, in every version in the store unless
+        /// given as `name-version`
+        #[arg(add = installed_packages())]
+        package: Option<String>,
+    },
+    /// Makes packages read only again, like they are once installed
+    Lock {
+        /// Only locks this package, in every version in the store unless
+        /// given as `name-version`
+        #[arg(add = installed_packages())]
+        package: Option<String>,
+    },
+}
+
+/// Which packages `dpt list` shows, all of them by default.
+#[derive(Args, Debug)]
+#[group(multiple = false)]
+pub struct ListFilter {
+    /// Only lists the packages that `dpt.lock` references
+    #[arg(long)]
+    pub locked: bool,
+    /// Only lists the packages that `dpt.lock` doesn't reference
+    #[arg(long)]
+    pub orphans: bool,
+    /// Only lists the locked packages with newer versions in the repositories
+    #[arg(long)]
+    pub upgradable: bool,
+}
+
+/// The options for how packages are run.
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    /// Sets an environment variable inside of the environment
+    #[arg(short, long = "env", value_name = "VAR=VALUE", value_parser = parse_env_var)]
+    env: Vec<(String, String)>,
+    /// Doesn't pass the caller's environment variables along
+    #[arg(long)]
+    clear_env: bool,
+    /// The absolute working directory inside of the environment
+    #[arg(long, value_parser = parse_absolute_path)]
+    cwd: Option<PathBuf>,
+}
+
+impl RunArgs {
+    pub fn into_options(self) -> RunOptions {
+        RunOptions {
+            env: self.env,
+            clear_env: self.clear_env,
+            cwd: self.cwd,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct CmdArgs {
+    /// The command to run instead of the first package's
+    #[arg(short, long)]
+    pub cmd: Option<String>,
+}
+
+/// The options shared by `gen-index` and the `repo` commands.
+#[derive(Args, Debug)]
+pub struct IndexArgs {
+    /// The directory to search for `.dpt` files
+    #[arg(long, global = true, default_value = ".")]
+    root: PathBuf,
+    /// Where to write the index, instead of `index.kdl` inside of the root
+    #[arg(short, long, global = true)]
+    output: Option<PathBuf>,
+    /// Skips paths relative to the root matching the glob
+    #[arg(short, long, global = true, value_name = "GLOB")]
+    exclude: Vec<glob::Pattern>,
+    /// Writes paths as absolute URLs below this one
+    #[arg(long, global = true, value_name = "URL")]
+    base_url: Option<String>,
+    /// Doesn't follow symlinks while searching for `.dpt` files
+    #[arg(long, global = true)]
+    no_follow_links: bool,
+    /// Also writes zstd compressed copies of the index files
+    #[arg(long, global = true)]
+    compress: bool,
+    /// Splits the index into one shard per first letter of package names
+    #[arg(long, global = true)]
+    split: bool,
+}
+
+impl IndexArgs {
+    pub fn into_options(self) -> GenIndexOptions {
+        GenIndexOptions {
+            root: self.root,
+            output: self.output,
+            exclude: self.exclude,
+            base_url: self.base_url,
+            follow_links: !self.no_follow_links,
+            compress: self.compress,
+            split: self.split,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+fn parse_env_var(var: &str) -> Result<(String, String), String> {
+    match var.split_once('=') {
+        Some((name, value)) => Ok((name.to_string(), value.to_string())),
+        None => Err("needs to be given as VAR=value".to_string()),
+    }
+}
+
+fn parse_absolute_path(path: &str) -> Result<PathBuf, String> {
+    match path.starts_with('/') {
+        true => Ok(PathBuf::from(path)),
+        false => Err("needs to be an absolute path".to_string()),
+    }
+}
+
+/// Completes the names of the packages in the store.
+fn installed_packages() -> ArgValueCandidates {
+    ArgValueCandidates::new(|| {
+        let mut names = std::fs::read_dir(get_store_location())
+            .into_iter()
+            .flatten()
+            .filter_map(|x| x.ok())
+            .filter_map(|x| {
+                string_to_package(&x.file_name().to_string_lossy()).ok()
+            })
+            .map(|x| x.name)
+            .collect::<Vec<String>>();
+        names.sort();
+        names.dedup();
+        names.into_iter().map(CompletionCandidate::new).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli_is_valid() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn run_arguments() {
+        let cli = Cli::try_parse_from([
+            "dpt", "run", "--env", "A=1=2", "--cwd", "/tmp", "foo", "--help",
+        ])
+        .unwrap();
+        let Command::Run { options, command } = cli.command else {
+            panic!("Not parsed as run");
+        };
+        assert_eq!(options.env, [("A".to_string(), "1=2".to_string())]);
+        assert_eq!(command, ["foo", "--help"]);
+
+        let cli = Cli::try_parse_from([
+            "dpt",
+            "run-multi",
+            "foo",
+            "--cmd",
+            "sh",
+            "bar",
+            "--",
+            "-c",
+            "x",
+        ])
+        .unwrap();
+        let Command::RunMulti {
+            cmd,
+            packages,
+            args,
+            ..
+        } = cli.command
+        else {
+            panic!("Not parsed as run-multi");
+        };
+        assert_eq!(cmd.cmd.as_deref(), Some("sh"));
+        assert_eq!(packages, ["foo", "bar"]);
+        assert_eq!(args, ["-c", "x"]);
+
+        Cli::try_parse_from(["dpt", "run", "--cwd", "tmp", "foo"]).unwrap_err();
+        Cli::try_parse_from(["dpt", "rebiuld"]).unwrap_err();
+        assert!(
+            Cli::try_parse_from(["dpt", "rebuild", "--json"])
+                .unwrap()
+                .json
+        );
+    }
+}
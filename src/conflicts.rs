@@ -0,0 +1,321 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use kdl::{KdlDocument, KdlEntry, KdlNode};
+use walkdir::WalkDir;
+
+use crate::{
+    dpt_file::{get_dpt_lock_location, read_dpt_lock_file},
+    pkg::{parse_kdl, PackageConfig},
+    store::read_package_config,
+};
+
+/// Files that more than one package of an environment has.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Conflict {
+    /// The packages having the files, starting with the one whose files are
+    /// used.
+    pub packages: Vec<String>,
+    /// Paths relative to the root of the environment.
+    pub paths: Vec<PathBuf>,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let list = |items: &[String]| match items {
+            [] => String::new(),
+            [x] => x.clone(),
+            [rest @ .., last] => format!("{} and {}", rest.join(", "), last),
+        };
+        let mut paths = self
+            .paths
+            .iter()
+            .take(3)
+            .map(|x| format!("/{}", x.display()))
+            .collect::<Vec<String>>();
+        if self.paths.len() > 3 {
+            paths.push(format!("{} more", self.paths.len() - 3));
+        }
+        write!(
+            f,
+            "{} have the same files ({}), using the ones of {}",
+            list(&self.packages),
+            list(&paths),
+            self.packages[0]
+        )
+    }
+}
+
+/// Reads the configs of the package layers, which is every layer but `base`.
+fn read_layer_configs(
+    layers: &[PathBuf],
+) -> Result<Vec<Option<PackageConfig>>> {
+    layers
+        .iter()
+        .map(|x| match x.join("dpt/pkg.kdl").is_file() {
+            true => read_package_config(x).map(Some),
+            false => Ok(None),
+        })
+        .collect()
+}
+
+/// Gets the order the layers should be in, moving packages in front of the
+/// ones they replace, so that their files are the ones used.
+fn replacement_order(configs: &[Option<PackageConfig>]) -> Vec<usize> {
+    let mut order = (0..configs.len()).collect::<Vec<usize>>();
+    // Bounded, as packages replacing each other would be moved forever
+    for _ in 0..configs.len() {
+        let mut moved = false;
+        for pos in 0..order.len() {
+            let Some(config) = &configs[order[pos]] else {
+                continue;
+            };
+            let replaced = order[..pos].iter().position(|x| {
+                configs[*x]
+                    .as_ref()
+                    .is_some_and(|x| config.replaces.contains(&x.name))
+            });
+            if let Some(target) = replaced {
+                let layer = order.remove(pos);
+                order.insert(target, layer);
+                moved = true;
+            }
+        }
+        if !moved {
+            break;
+        }
+    }
+    order
+}
+
+/// Orders the layers of an environment so that packages replacing others
+/// come first, bailing if it has packages that conflict with each other.
+pub fn order_layers(layers: &mut Vec<PathBuf>) -> Result<()> {
+    let configs = read_layer_configs(layers)?;
+    for config in configs.iter().flatten() {
+        for other in configs.iter().flatten() {
+            if config.name != other.name
+                && config.conflicts.contains(&other.name)
+            {
+                bail!(
+                    "{} conflicts with {}, so they can't be in the same environment!",
+                    config.name,
+                    other.name
+                );
+            }
+        }
+    }
+    let order = replacement_order(&configs);
+    *layers = order.into_iter().map(|x| layers[x].clone()).collect();
+    Ok(())
+}
+
+/// Finds the files that more than one package of the environment made of
+/// layers has, except for the ones the package whose files are used
+/// declares it replaces. Symlinks pointing at the same place don't conflict.
+pub fn find_conflicts(layers: &[PathBuf]) -> Result<Vec<Conflict>> {
+    let configs = read_layer_configs(layers)?;
+    let mut owners = BTreeMap::<PathBuf, Vec<usize>>::new();
+    for (i, layer) in layers.iter().enumerate() {
+        if configs[i].is_none() {
+            continue;
+        }
+        for ent in WalkDir::new(layer)
+            .min_depth(1)
+            .into_iter()
+            .filter_entry(|x| x.path() != layer.join("dpt"))
+            .filter_map(|e| e.ok())
+        {
+            if ent.file_type().is_dir() {
+                continue;
+            }
+            let path = ent.path().strip_prefix(layer)?.to_path_buf();
+            owners.entry(path).or_default().push(i);
+        }
+    }
+
+    let mut groups = BTreeMap::<Vec<usize>, Vec<PathBuf>>::new();
+    for (path, layer_ids) in owners {
+        if layer_ids.len() < 2 || same_symlinks(layers, &layer_ids, &path) {
+            continue;
+        }
+        groups.entry(layer_ids).or_default().push(path);
+    }
+
+    let mut conflicts = Vec::<Conflict>::new();
+    for (layer_ids, paths) in groups {
+        let packages = layer_ids
+            .iter()
+            .filter_map(|x| configs[*x].as_ref())
+            .collect::<Vec<&PackageConfig>>();
+        if packages[1..]
+            .iter()
+            .all(|x| packages[0].replaces.contains(&x.name))
+        {
+            continue;
+        }
+        conflicts.push(Conflict {
+            packages: packages.iter().map(|x| x.name.clone()).collect(),
+            paths,
+        });
+    }
+    Ok(conflicts)
+}
+
+/// Checks whether path is a symlink to the same place in each of the layers.
+fn same_symlinks(layers: &[PathBuf], layer_ids: &[usize], path: &Path) -> bool {
+    let targets = layer_ids
+        .iter()
+        .map(|x| std::fs::read_link(layers[*x].join(path)).ok())
+        .collect::<Vec<Option<PathBuf>>>();
+    targets[0].is_some() && targets.iter().all(|x| *x == targets[0])
+}
+
+/// Writes conflicts to path, to be read back by `read_conflicts`.
+pub fn write_conflicts(path: &Path, conflicts: &[Conflict]) -> Result<()> {
+    let mut doc = KdlDocument::new();
+    for conflict in conflicts {
+        let mut node = KdlNode::new("conflict");
+        for pkg in &conflict.packages {
+            node.push(KdlEntry::new(pkg.clone()));
+        }
+        let mut paths = KdlDocument::new();
+        for path in &conflict.paths {
+            let mut child = KdlNode::new("-");
+            child.push(KdlEntry::new(path.to_string_lossy().to_string()));
+            paths.nodes_mut().push(child);
+        }
+        node.set_children(paths);
+        doc.nodes_mut().push(node);
+    }
+    std::fs::write(path, doc.to_string())?;
+    Ok(())
+}
+
+/// Reads conflicts written by `write_conflicts`.
+pub fn read_conflicts(path: &Path) -> Result<Vec<Conflict>> {
+    let doc = parse_kdl(&std::fs::read_to_string(path)?)?;
+    let strings = |node: &KdlNode| {
+        node.entries()
+            .iter()
+            .filter_map(|x| x.value().as_string().map(|x| x.to_string()))
+            .collect::<Vec<String>>()
+    };
+    Ok(doc
+        .nodes()
+        .iter()
+        .map(|node| Conflict {
+            packages: strings(node),
+            paths: node
+                .children()
+                .map(|x| x.nodes())
+                .unwrap_or_default()
+                .iter()
+                .flat_map(strings)
+                .map(PathBuf::from)
+                .collect(),
+        })
+        .collect())
+}
+
+/// Checks whether the dpt file asks to refuse running packages with
+/// conflicts that aren't declared, as of the last rebuild.
+pub fn conflicts_are_strict() -> Result<bool> {
+    if !get_dpt_lock_location().exists() {
+        return Ok(false);
+    }
+    Ok(read_dpt_lock_file()?.strict_conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(name: &str, replaces: &[&str]) -> PackageConfig {
+        PackageConfig {
+            name: name.to_string(),
+            version: "1.0.0".to_string(),
+            depends: vec![],
+            provides_plugs: vec![],
+            plugs: vec![],
+            exports: vec![],
+            env: vec![],
+            conflicts: vec![],
+            replaces: replaces.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn conflicting_files() {
+        let dir = std::env::temp_dir()
+            .join(format!("dpt-conflicts-{}", std::process::id()));
+        let layers = ["base", "foo", "bar", "baz"].map(|x| dir.join(x));
+        for (layer, replaces) in
+            layers[1..].iter().zip(["", "", "replaces foo\n"])
+        {
+            let name = layer.file_name().unwrap().to_string_lossy();
+            std::fs::create_dir_all(layer.join("dpt")).unwrap();
+            std::fs::create_dir_all(layer.join("usr/bin")).unwrap();
+            std::fs::write(
+                layer.join("dpt/pkg.kdl"),
+                format!("name {}\nversion \"1.0.0\"\n{}", name, replaces),
+            )
+            .unwrap();
+            std::os::unix::fs::symlink(
+                "../lib/tool",
+                layer.join("usr/bin/tool"),
+            )
+            .unwrap();
+        }
+        std::fs::create_dir_all(layers[0].join("etc")).unwrap();
+        for file in ["base/etc/passwd", "foo/etc/passwd", "foo/usr/bin/x"] {
+            std::fs::create_dir_all(dir.join(file).parent().unwrap()).unwrap();
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        for file in ["bar/usr/bin/x", "baz/usr/bin/x", "baz/usr/bin/y"] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        std::fs::write(dir.join("bar/usr/bin/y"), "").unwrap();
+
+        // baz replacing foo doesn't cover bar, and base never conflicts
+        let mut layers = layers.to_vec();
+        order_layers(&mut layers).unwrap();
+        assert_eq!(layers[1], dir.join("baz"));
+        let conflicts = find_conflicts(&layers).unwrap();
+        assert_eq!(
+            conflicts,
+            [
+                Conflict {
+                    packages: vec!["baz".into(), "foo".into(), "bar".into()],
+                    paths: vec![PathBuf::from("usr/bin/x")],
+                },
+                Conflict {
+                    packages: vec!["baz".into(), "bar".into()],
+                    paths: vec![PathBuf::from("usr/bin/y")],
+                },
+            ]
+        );
+
+        let file = dir.join("conflicts");
+        write_conflicts(&file, &conflicts).unwrap();
+        assert_eq!(read_conflicts(&file).unwrap(), conflicts);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replacements_come_first() {
+        let configs = vec![
+            None,
+            Some(config("a", &[])),
+            Some(config("b", &[])),
+            Some(config("c", &["a"])),
+            Some(config("d", &["c"])),
+        ];
+        assert_eq!(replacement_order(&configs), [0, 4, 3, 1, 2]);
+    }
+}
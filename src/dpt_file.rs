@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 use anyhow::anyhow;
@@ -7,10 +8,14 @@ use anyhow::Result;
 use kdl::KdlDocument;
 use kdl::KdlEntry;
 use kdl::KdlNode;
+use kdl::KdlNodeFormat;
 use kdl::KdlValue;
 
+use crate::index::write_atomic;
 use crate::pkg::parse_kdl;
 use crate::pkg::Package;
+use crate::sandbox::parse_sandbox_overrides;
+use crate::sandbox::Sandbox;
 use crate::store::get_dpt_dir;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -35,6 +40,13 @@ pub struct DptFile {
     pub packages: Vec<Package>,
     pub users: Vec<User>,
     pub groups: Vec<Group>,
+    /// Sandbox options overriding the profiles of packages, by package name.
+    pub sandbox: HashMap<String, Sandbox>,
+    /// Whether to refuse running packages with conflicting files that aren't
+    /// declared.
+    pub strict_conflicts: bool,
+    /// Whether to set the immutable attribute on the packages in the store.
+    pub immutable_store: bool,
 }
 
 fn kdlvalue_as_string(v: &KdlValue, n: &str) -> Result<String> {
@@ -144,6 +156,19 @@ pub fn parse_dpt_file(file: &KdlDocument) -> Result<DptFile> {
         packages,
         users,
         groups,
+        sandbox: parse_sandbox_overrides(file)?,
+        strict_conflicts: match file.get_arg("strict-conflicts") {
+            Some(x) => x
+                .as_bool()
+                .ok_or(anyhow!("strict-conflicts is not a boolean!"))?,
+            None => false,
+        },
+        immutable_store: match file.get_arg("immutable-store") {
+            Some(x) => x
+                .as_bool()
+                .ok_or(anyhow!("immutable-store is not a boolean!"))?,
+            None => false,
+        },
     })
 }
 
@@ -167,6 +192,153 @@ pub fn read_dpt_lock_file() -> Result<DptFile> {
     )?)?)
 }
 
+/// Reads the dpt file as a raw KDL document, for editing it in place.
+/// Returns an empty document if the dpt file does not exist yet.
+pub fn read_dpt_file_document() -> Result<KdlDocument> {
+    let location = get_dpt_file_location();
+    if !location.exists() {
+        return Ok(KdlDocument::new());
+    }
+    parse_kdl(&std::fs::read_to_string(location)?)
+}
+
+/// Writes the dpt file, replacing it at once so that it's never left half
+/// written when dpt is interrupted.
+pub fn write_dpt_file_document(doc: &KdlDocument) -> Result<()> {
+    write_atomic(&get_dpt_file_location(), doc.to_string())
+        .context("Failed to write dpt file")
+}
+
+/// Gets the whitespace that the nodes inside of `children` are indented with.
+fn children_indent(children: &KdlDocument) -> String {
+    let leading = match children.nodes().last().and_then(|x| x.format()) {
+        Some(x) if !x.leading.is_empty() => x.leading.clone(),
+        _ => children
+            .format()
+            .map(|x| x.leading.clone())
+            .unwrap_or_default(),
+    };
+    let indent = leading.rsplit('\n').next().unwrap_or("");
+    if indent.chars().all(|x| x == ' ' || x == '\t') {
+        indent.to_string()
+    } else {
+        "    ".to_string()
+    }
+}
+
+/// Adds a package to the `packages` node of a dpt file, keeping the
+/// formatting and comments of the rest of the document. If the package is
+/// already listed, its version is replaced, unless pkg has none, in which case
+/// the version it's pinned to is kept. Returns false if nothing changed.
+pub fn add_package_to_dpt_document(
+    doc: &mut KdlDocument,
+    pkg: &Package,
+) -> bool {
+    if doc.get("packages").is_none() {
+        if let Some(last) = doc.nodes_mut().last_mut() {
+            if let Some(format) = last.format_mut() {
+                if !format.terminator.ends_with('\n')
+                    && !format.trailing.ends_with('\n')
+                {
+                    format.terminator.push('\n');
+                }
+            }
+        }
+        let mut packages = KdlNode::new("packages");
+        packages.ensure_children();
+        doc.nodes_mut().push(packages);
+    }
+    let packages = doc
+        .get_mut("packages")
+        .expect("`packages` node was just inserted");
+    let children = packages.ensure_children();
+
+    if let Some(existing) = children
+        .nodes_mut()
+        .iter_mut()
+        .find(|x| x.name().value() == pkg.name)
+    {
+        let version = existing
+            .entries()
+            .first()
+            .and_then(|x| x.value().as_string())
+            .unwrap_or("");
+        if version == pkg.version || pkg.version.is_empty() {
+            return false;
+        }
+        existing.entries_mut().clear();
+        if !pkg.version.is_empty() {
+            existing.push(KdlEntry::new(pkg.version.clone()));
+        }
+        return true;
+    }
+
+    let mut node = KdlNode::new(pkg.name.as_str());
+    if !pkg.version.is_empty() {
+        node.push(KdlEntry::new(pkg.version.clone()));
+    }
+
+    let newline_terminated = children
+        .nodes()
+        .last()
+        .and_then(|x| x.format())
+        .is_some_and(|x| x.terminator.ends_with('\n'));
+    if newline_terminated {
+        node.set_format(KdlNodeFormat {
+            leading: children_indent(children),
+            terminator: "\n".to_string(),
+            ..Default::default()
+        });
+        children.nodes_mut().push(node);
+    } else {
+        children.nodes_mut().push(node);
+        packages.autoformat();
+    }
+    true
+}
+
+/// Removes a package from the `packages` node of a dpt file, keeping the
+/// formatting and comments of the rest of the document. Returns false if the
+/// package is not listed.
+pub fn remove_package_from_dpt_document(
+    doc: &mut KdlDocument,
+    name: &str,
+) -> bool {
+    let packages = match doc.get_mut("packages") {
+        Some(x) => x,
+        None => return false,
+    };
+    let children = packages.ensure_children();
+    let index = match children
+        .nodes()
+        .iter()
+        .position(|x| x.name().value() == name)
+    {
+        Some(x) => x,
+        None => return false,
+    };
+    let removed = children.nodes_mut().remove(index);
+
+    if children.nodes().is_empty() {
+        packages.autoformat();
+        return true;
+    }
+
+    // The first node's indentation lives in the document's leading whitespace,
+    // so the node taking its place must not bring its own.
+    if index == 0 {
+        let removed_leading = removed
+            .format()
+            .map(|x| x.leading.clone())
+            .unwrap_or_default();
+        if let Some(format) = children.nodes_mut()[0].format_mut() {
+            format.leading = removed_leading
+                + format.leading.trim_start_matches([' ', '\t']);
+        }
+    }
+    true
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -233,6 +405,100 @@ users {
         )
     }
 
+    #[test]
+    fn add_package_keeps_formatting() {
+        let mut doc: KdlDocument = r#"// System configuration
+packages {
+    gcc // The compiler
+    // Shells
+    fish "4.0.0"
+}
+
+users {
+}
+"#
+        .parse()
+        .unwrap();
+
+        assert!(add_package_to_dpt_document(
+            &mut doc,
+            &Package::new("yazi".into(), "".into())
+        ));
+        assert!(add_package_to_dpt_document(
+            &mut doc,
+            &Package::new("fish".into(), "4.1.0".into())
+        ));
+        assert!(!add_package_to_dpt_document(
+            &mut doc,
+            &Package::new("gcc".into(), "".into())
+        ));
+        // Adding it without a version keeps the pin
+        assert!(!add_package_to_dpt_document(
+            &mut doc,
+            &Package::new("fish".into(), "".into())
+        ));
+
+        assert_eq!(
+            doc.to_string(),
+            r#"// System configuration
+packages {
+    gcc // The compiler
+    // Shells
+    fish "4.1.0"
+    yazi
+}
+
+users {
+}
+"#
+        );
+    }
+
+    #[test]
+    fn add_package_without_packages_node() {
+        let mut doc: KdlDocument = "users {\n}".parse().unwrap();
+        assert!(add_package_to_dpt_document(
+            &mut doc,
+            &Package::new("gcc".into(), "14.2.0".into())
+        ));
+
+        let out = parse_dpt_file(&doc).unwrap();
+        assert_eq!(
+            out.packages,
+            vec![Package::new("gcc".into(), "14.2.0".into())]
+        );
+        assert_eq!(
+            doc.to_string(),
+            "users {\n}\npackages {\n    gcc \"14.2.0\"\n}\n"
+        );
+    }
+
+    #[test]
+    fn remove_package_keeps_formatting() {
+        let mut doc: KdlDocument = r#"packages {
+    gcc // The compiler
+    // Shells
+    fish "4.0.0"
+    yazi
+}
+"#
+        .parse()
+        .unwrap();
+
+        assert!(remove_package_from_dpt_document(&mut doc, "gcc"));
+        assert!(remove_package_from_dpt_document(&mut doc, "yazi"));
+        assert!(!remove_package_from_dpt_document(&mut doc, "binutils"));
+
+        assert_eq!(
+            doc.to_string(),
+            r#"packages {
+    // Shells
+    fish "4.0.0"
+}
+"#
+        );
+    }
+
     #[test]
     fn groups_array() {
         let doc: KdlDocument = r#"
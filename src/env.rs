@@ -10,6 +10,7 @@ use log::warn;
 use walkdir::WalkDir;
 
 use crate::{
+    conflicts::order_layers,
     pkg::Package,
     repo::{
         package_to_onlinepackage, resolve_dependencies_for_package,
@@ -18,11 +19,46 @@ use crate::{
     store::get_dpt_dir,
 };
 
-/// Generates the environment for a package, version solving to find dependencies.
-pub fn generate_environment_for_package(
+/// How files from the store are placed into an environment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkMode {
+    /// Hardlinks, which need the environment on the same filesystem as the
+    /// store and permission to link files owned by root.
+    Hardlink,
+    /// Absolute symlinks into the store, which resolve inside of the
+    /// environment because the dpt directory is mounted at the same path.
+    Symlink,
+}
+
+/// Gets the directories that make up the environment of pkgs, starting with
+/// `base`. Files from earlier directories take priority over later ones, and
+/// packages come before the ones they replace.
+pub fn get_environment_layers(
+    pkgs: &Vec<Package>,
+    installed: &Vec<OnlinePackage>,
+) -> Result<Vec<PathBuf>> {
+    let mut layers = Vec::<PathBuf>::new();
+    if get_dpt_dir().join("base").is_dir() {
+        layers.push(get_dpt_dir().join("base"));
+    } else {
+        warn!("`base` is not found!");
+    }
+    let mut done_list: Vec<Package> = Vec::new();
+    for pkg in pkgs {
+        if !done_list.contains(pkg) {
+            add_package_layers(pkg, installed, &mut layers, &mut done_list)?;
+        }
+    }
+    order_layers(&mut layers)?;
+    Ok(layers)
+}
+
+/// Adds the directory of a package to layers, version solving to find
+/// dependencies and adding theirs afterwards.
+fn add_package_layers(
     pkg: &Package,
     pkgs: &Vec<OnlinePackage>,
-    out_path: &Path,
+    layers: &mut Vec<PathBuf>,
     done_list: &mut Vec<Package>,
 ) -> Result<()> {
     let package = package_to_onlinepackage(pkg, pkgs)?;
@@ -34,31 +70,12 @@ pub fn generate_environment_for_package(
         pkg_data_dir.display()
     ))?;
 
-    if done_list.is_empty() {
-        if let Ok(x) = std::fs::exists(out_path) {
-            if x {
-                std::fs::remove_dir_all(out_path)?;
-            }
-        }
-        std::fs::DirBuilder::new()
-            .recursive(true)
-            .create(out_path)?;
-        if get_dpt_dir().join("base").is_dir() {
-            generate_environment_for_directory(
-                &get_dpt_dir().join("base"),
-                &out_path,
-            )?;
-        } else {
-            warn!("`base` is not found!");
-        }
-    }
-
-    generate_environment_for_directory(&pkg_data_dir, &out_path)?;
+    layers.push(pkg_data_dir);
 
     done_list.push(pkg.clone());
 
     // Convert dependencies into packages by version solving
-    let dependencies = resolve_dependencies_for_package(&pkgs, pkg)
+    let dependencies = resolve_dependencies_for_package(pkgs, pkg)
         .context(anyhow!("Failed to resolve dependencies"))?;
 
     for dependency in dependencies {
@@ -69,16 +86,62 @@ pub fn generate_environment_for_package(
             name: dependency.name,
             version: dependency.version,
         };
-        generate_environment_for_package(&p, pkgs, out_path, done_list)?;
-        done_list.push(p);
+        add_package_layers(&p, pkgs, layers, done_list)?;
     }
 
     Ok(())
 }
 
+/// Generates an environment at out_path by linking the files of each layer
+/// into it.
+pub fn link_environment(
+    layers: &[PathBuf],
+    out_path: &Path,
+    mode: LinkMode,
+) -> Result<()> {
+    if let Ok(true) = std::fs::exists(out_path) {
+        std::fs::remove_dir_all(out_path)?;
+    }
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .create(out_path)?;
+    for layer in layers {
+        generate_environment_for_directory(layer, out_path, mode)?;
+    }
+    Ok(())
+}
+
+/// Checks whether stacking the layers with overlayfs gives the same result as
+/// linking them. Linking follows a symlinked directory like `base`'s
+/// `lib64 -> usr/lib` and puts a later layer's `lib64` files inside of it,
+/// while overlayfs would hide them behind the symlink.
+pub fn layers_can_be_stacked(layers: &[PathBuf]) -> bool {
+    let mut symlinks = Vec::<PathBuf>::new();
+    for layer in layers {
+        for ent in WalkDir::new(layer)
+            .min_depth(1)
+            .max_depth(2)
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            let Ok(path) = ent.path().strip_prefix(layer) else {
+                continue;
+            };
+            if ent.file_type().is_dir() && symlinks.iter().any(|x| x == path) {
+                return false;
+            }
+            if ent.path_is_symlink() && ent.path().is_dir() {
+                symlinks.push(path.to_path_buf());
+            }
+        }
+    }
+    true
+}
+
 pub fn generate_environment_for_directory(
     pkg_data_dir: &Path,
     out_path: &Path,
+    mode: LinkMode,
 ) -> Result<()> {
     for ent in WalkDir::new(&pkg_data_dir)
         .into_iter()
@@ -95,12 +158,31 @@ pub fn generate_environment_for_directory(
             if target_path.exists() || target_path.is_symlink() {
                 continue; // Another package with higher priority then us put a file here.
             }
-            if source_path.is_file() {
-                hard_link(source_path, &target_path).context(anyhow!(
+            if source_path.is_file() && mode == LinkMode::Symlink {
+                symlink(source_path, &target_path).context(anyhow!(
                     "In creating an symlink for environment [{} -> {}]",
                     source_path.display(),
                     target_path.display()
                 ))?;
+            } else if source_path.is_file() {
+                match hard_link(source_path, &target_path) {
+                    // Immutable files of the store can't be linked to
+                    Err(e)
+                        if e.kind() == std::io::ErrorKind::PermissionDenied =>
+                    {
+                        Err(e).context(anyhow!(
+                            "Failed to hardlink {} into the environment, it \
+                            may be immutable without immutable-store having \
+                            been set by the last rebuild",
+                            source_path.display()
+                        ))
+                    }
+                    x => x.context(anyhow!(
+                        "In creating an symlink for environment [{} -> {}]",
+                        source_path.display(),
+                        target_path.display()
+                    )),
+                }?;
             } else {
                 symlink(read_link(&source_path)?, &target_path)?;
             }
@@ -113,3 +195,28 @@ pub fn generate_environment_for_directory(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layers_with_symlinked_directories() {
+        let dir = std::env::temp_dir()
+            .join(format!("dpt-layers-{}", std::process::id()));
+        let base = dir.join("base");
+        let pkg = dir.join("pkg");
+        fs::create_dir_all(base.join("usr/lib")).unwrap();
+        fs::create_dir_all(pkg.join("usr/lib")).unwrap();
+        symlink("usr/lib", base.join("lib64")).unwrap();
+
+        let layers = vec![base.clone(), pkg.clone()];
+        assert!(layers_can_be_stacked(&layers));
+        fs::create_dir_all(pkg.join("lib64")).unwrap();
+        assert!(!layers_can_be_stacked(&layers));
+        // An earlier directory is used as is, like when linking
+        assert!(layers_can_be_stacked(&[pkg, base]));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
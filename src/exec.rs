@@ -0,0 +1,265 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use nix::{
+    libc,
+    sys::prctl,
+    unistd::{setgroups, Gid},
+};
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter};
+
+use crate::{run::Backend, sandbox::Seccomp, userns};
+
+/// System calls denied by the default filter.
+const DEFAULT_DENIED: &[libc::c_long] = &[
+    libc::SYS_acct,
+    libc::SYS_add_key,
+    libc::SYS_bpf,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_fsconfig,
+    libc::SYS_fsmount,
+    libc::SYS_fsopen,
+    libc::SYS_fspick,
+    libc::SYS_init_module,
+    libc::SYS_kexec_load,
+    libc::SYS_keyctl,
+    libc::SYS_mount,
+    libc::SYS_move_mount,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_open_tree,
+    libc::SYS_perf_event_open,
+    libc::SYS_pivot_root,
+    libc::SYS_quotactl,
+    libc::SYS_reboot,
+    libc::SYS_request_key,
+    libc::SYS_setns,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_syslog,
+    libc::SYS_umount2,
+    libc::SYS_unshare,
+    libc::SYS_userfaultfd,
+];
+
+/// System calls denied by the strict filter, on top of the default ones.
+const STRICT_DENIED: &[libc::c_long] = &[
+    libc::SYS_adjtimex,
+    libc::SYS_chroot,
+    libc::SYS_clock_adjtime,
+    libc::SYS_clock_settime,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_personality,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_setdomainname,
+    libc::SYS_sethostname,
+    libc::SYS_settimeofday,
+];
+
+/// Compiles the seccomp filter, which makes denied system calls fail with
+/// EPERM. Returns None when it is off.
+pub fn compile_seccomp_filter(seccomp: Seccomp) -> Result<Option<BpfProgram>> {
+    let denied = match seccomp {
+        Seccomp::Off => return Ok(None),
+        Seccomp::Default => DEFAULT_DENIED.to_vec(),
+        Seccomp::Strict => [DEFAULT_DENIED, STRICT_DENIED].concat(),
+    };
+    let rules = denied
+        .into_iter()
+        .map(|x| (x, vec![]))
+        .collect::<BTreeMap<_, _>>();
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        std::env::consts::ARCH.try_into()?,
+    )?;
+    Ok(Some(filter.try_into().map_err(|e| {
+        anyhow!("Failed to compile seccomp filter: {}", e)
+    })?))
+}
+
+/// Reads the first `etc/<file>` found in layers, which is the one the
+/// environment sees.
+pub fn read_environment_file(layers: &[PathBuf], file: &str) -> Option<String> {
+    layers
+        .iter()
+        .map(|x| x.join("etc").join(file))
+        .find(|x| x.exists())
+        .and_then(|x| std::fs::read_to_string(x).ok())
+}
+
+/// Gets the groups of uid from the `/etc/passwd` and `/etc/group` generated
+/// into `base` from the dpt file, with the primary group first. Those of
+/// packages are never read, as they would decide which groups the process
+/// gets. None if `base` has no `/etc/group`.
+pub fn get_groups(base: &Path, uid: u32, gid: u32) -> Option<Vec<Gid>> {
+    let group = std::fs::read_to_string(base.join("etc/group")).ok()?;
+    let mut groups = vec![Gid::from_raw(gid)];
+    let Some(username) = std::fs::read_to_string(base.join("etc/passwd"))
+        .ok()
+        .and_then(|x| {
+            x.lines()
+                .map(|l| l.split(':').collect::<Vec<&str>>())
+                .find(|f| f.len() > 2 && f[2] == uid.to_string())
+                .map(|f| f[0].to_string())
+        })
+    else {
+        return Some(groups);
+    };
+    for line in group.lines() {
+        let fields = line.split(':').collect::<Vec<&str>>();
+        if fields.len() < 4 || !fields[3].split(',').any(|x| x == username) {
+            continue;
+        }
+        match fields[2].parse::<u32>().map(Gid::from_raw) {
+            Ok(x) if !groups.contains(&x) => groups.push(x),
+            _ => {}
+        }
+    }
+    Some(groups)
+}
+
+/// The header of capget and capset, from `linux/capability.h`.
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+/// The version of the capability structures with 64 bit sets.
+const CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+/// Drops every capability from the bounding set, so that exec can't give
+/// any back.
+fn clear_bounding_set() -> io::Result<()> {
+    // prctl is variadic, so its arguments have to be of the right size
+    let unused: libc::c_ulong = 0;
+    // Reading a capability the kernel doesn't know fails, ending the list
+    for cap in 0 as libc::c_ulong.. {
+        let read = unsafe {
+            libc::prctl(libc::PR_CAPBSET_READ, cap, unused, unused, unused)
+        };
+        match read {
+            1 => {}
+            0 => continue,
+            _ => break,
+        }
+        let res = unsafe {
+            libc::prctl(libc::PR_CAPBSET_DROP, cap, unused, unused, unused)
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Empties the ambient, inheritable, permitted and effective capability
+/// sets.
+fn clear_capabilities() -> io::Result<()> {
+    let unused: libc::c_ulong = 0;
+    let res = unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong,
+            unused,
+            unused,
+            unused,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let header = CapHeader {
+        version: CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    // Effective, permitted and inheritable, for each half of the sets
+    let data = [0u32; 6];
+    let res =
+        unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Drops everything the process doesn't need before it execs a package: it
+/// gets the groups of the environment, loses all capabilities and can't gain
+/// new privileges through setuid binaries. Meant to be called between fork
+/// and exec, as the last step, so it only makes system calls.
+pub fn harden(
+    backend: Backend,
+    uid: u32,
+    gid: u32,
+    groups: Option<&[Gid]>,
+    filter: Option<&BpfProgram>,
+) -> io::Result<()> {
+    // Supplementary groups can't be set in a user namespace, since setgroups
+    // is denied there so that groups can't be dropped to get around
+    // permissions. Without groups from `base`, the caller's are kept.
+    if let (Backend::Setuid, Some(groups)) = (backend, groups) {
+        setgroups(groups)?;
+    }
+    clear_bounding_set()?;
+    if backend == Backend::Setuid {
+        userns::drop_privileges(uid, gid)?;
+    }
+    clear_capabilities()?;
+    prctl::set_no_new_privs()?;
+    if let Some(filter) = filter {
+        seccompiler::apply_filter(filter).map_err(|e| match e {
+            seccompiler::Error::Prctl(x) | seccompiler::Error::Seccomp(x) => x,
+            _ => io::Error::from_raw_os_error(libc::EINVAL),
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_from_environment() {
+        let dir = std::env::temp_dir()
+            .join(format!("dpt-groups-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("etc")).unwrap();
+        std::fs::write(
+            dir.join("etc/passwd"),
+            "root:x:0:0::/root:/bin/sh\njohn:x:1000:100::/home/john:/bin/sh\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("etc/group"),
+            "users:*:100:john\nwheel:*:10:root,john\naudio:*:29:johnny\nvideo:*:44:george,john\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            get_groups(&dir, 1000, 100).unwrap(),
+            [100, 10, 44].map(Gid::from_raw)
+        );
+        assert_eq!(
+            get_groups(&dir, 1001, 1001).unwrap(),
+            [Gid::from_raw(1001)]
+        );
+        assert!(get_groups(Path::new("/nonexistent"), 1000, 100).is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn seccomp_filters_compile() {
+        assert!(compile_seccomp_filter(Seccomp::Off).unwrap().is_none());
+        assert!(compile_seccomp_filter(Seccomp::Default).unwrap().is_some());
+        assert!(compile_seccomp_filter(Seccomp::Strict).unwrap().is_some());
+    }
+}
@@ -0,0 +1,384 @@
+use std::{
+    collections::HashMap,
+    fs::Permissions,
+    os::unix::fs::{symlink, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use walkdir::WalkDir;
+
+use crate::{
+    pkg::{Package, PackageConfig},
+    store::{get_dpt_dir, get_package_dir, read_package_config},
+};
+
+/// Gets the directory with the launchers of packages for the host. Its `bin`
+/// is meant to be added to `PATH`, and its `share` to `XDG_DATA_DIRS`.
+pub fn get_exports_location() -> PathBuf {
+    get_dpt_dir().join("exports")
+}
+
+/// Quotes s for a POSIX shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Quotes s for the `Exec` key of a desktop entry, if it needs to be.
+fn desktop_quote(s: &str) -> String {
+    if s.chars().any(|c| " \t\n\"'\\><~|&;$*?#()`".contains(c)) {
+        let mut quoted = String::from("\"");
+        for c in s.chars() {
+            if "\"`$\\".contains(c) {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        quoted.push('"');
+        quoted
+    } else {
+        s.to_string()
+    }
+}
+
+/// Gets the arguments running cmd of pkg through dpt. The version of pkg is
+/// given, so that the version that was exported is the one that runs. Exports
+/// are regenerated on every rebuild, which keeps it up to date.
+fn run_command(dpt: &Path, pkg: &Package, cmd: &str) -> Vec<String> {
+    vec![
+        dpt.to_string_lossy().to_string(),
+        "run-multi".to_string(),
+        format!("{}-{}", pkg.name, pkg.version),
+        "--cmd".to_string(),
+        cmd.to_string(),
+        "--".to_string(),
+    ]
+}
+
+fn write_wrapper(
+    out: &Path,
+    dpt: &Path,
+    pkg: &Package,
+    cmd: &str,
+) -> Result<()> {
+    let command = run_command(dpt, pkg, cmd)
+        .iter()
+        .map(|x| shell_quote(x))
+        .collect::<Vec<String>>()
+        .join(" ");
+    std::fs::write(out, format!("#!/bin/sh\nexec {} \"$@\"\n", command))?;
+    std::fs::set_permissions(out, Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+/// Splits the program off of the value of an `Exec` key, handling it being
+/// quoted.
+fn split_exec(exec: &str) -> (String, &str) {
+    let exec = exec.trim_start();
+    if let Some(rest) = exec.strip_prefix('"') {
+        let mut program = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => {
+                    if let Some((_, c)) = chars.next() {
+                        program.push(c);
+                    }
+                }
+                '"' => return (program, &rest[i + 1..]),
+                c => program.push(c),
+            }
+        }
+        (program, "")
+    } else {
+        match exec.split_once(' ') {
+            Some((program, args)) => (program.to_string(), args),
+            None => (exec.to_string(), ""),
+        }
+    }
+}
+
+/// Gets what `dpt run-multi --cmd` runs for the program of an `Exec` key of
+/// pkg. Absolute paths are run as they are, unless an export of pkg points at
+/// them, and other programs only when they are commands of pkg.
+fn exec_command(
+    program: &str,
+    config: &PackageConfig,
+    commands: &[String],
+) -> Option<String> {
+    if program.starts_with('/') {
+        let export =
+            config.exports.iter().find(|x| x.path == Path::new(program));
+        Some(export.map_or(program, |x| &x.name).to_string())
+    } else {
+        commands
+            .contains(&program.to_string())
+            .then(|| program.to_string())
+    }
+}
+
+/// Gets the commands of a package that `dpt run-multi --cmd` finds by name,
+/// its exports followed by the files in its `bin` and `usr/bin`.
+fn get_runnable_commands(
+    config: &PackageConfig,
+    pkg_dir: &Path,
+) -> Result<Vec<String>> {
+    let mut commands: Vec<String> =
+        config.exports.iter().map(|x| x.name.clone()).collect();
+    for dir in ["bin", "usr/bin"] {
+        let dir = pkg_dir.join(dir);
+        if dir.is_dir() {
+            for ent in std::fs::read_dir(&dir)? {
+                commands.push(ent?.file_name().to_string_lossy().to_string());
+            }
+        }
+    }
+    Ok(commands)
+}
+
+/// Rewrites a desktop entry of pkg to launch it with dpt. Icons given as
+/// absolute paths are pointed at the exported ones. An `Exec` whose program
+/// dpt can't find in pkg is left as it is. commands are the ones of
+/// [`get_runnable_commands`].
+fn rewrite_desktop_entry(
+    entry: &str,
+    dpt: &Path,
+    pkg: &Package,
+    exports: &Path,
+    config: &PackageConfig,
+    commands: &[String],
+) -> String {
+    let mut out = String::new();
+    for line in entry.lines() {
+        if line.starts_with("TryExec=") {
+            continue;
+        }
+        if let Some(exec) = line.strip_prefix("Exec=") {
+            let (program, args) = split_exec(exec);
+            let Some(cmd) = exec_command(&program, config, commands) else {
+                warn!(
+                    "`{}` of a desktop entry of {} is not a command of it, \
+                    not running it through dpt",
+                    program, pkg
+                );
+                out.push_str(line);
+                out.push('\n');
+                continue;
+            };
+            let command = run_command(dpt, pkg, &cmd)
+                .iter()
+                .map(|x| desktop_quote(x))
+                .collect::<Vec<String>>()
+                .join(" ");
+            out.push_str(&format!("Exec={}", command));
+            if !args.trim().is_empty() {
+                out.push_str(&format!(" {}", args.trim_start()));
+            }
+        } else if let Some(icon) = line
+            .strip_prefix("Icon=")
+            .and_then(|x| x.strip_prefix("/usr/"))
+            .filter(|x| {
+                x.starts_with("share/icons/") || x.starts_with("share/pixmaps/")
+            })
+        {
+            out.push_str(&format!("Icon={}", exports.join(icon).display()));
+        } else {
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Gets the commands a package exports. Without an `exports` declaration,
+/// every file in its `usr/bin` is exported.
+fn get_exported_commands(
+    config: &PackageConfig,
+    pkg_dir: &Path,
+) -> Result<Vec<String>> {
+    if !config.exports.is_empty() {
+        return Ok(config.exports.iter().map(|x| x.name.clone()).collect());
+    }
+    let bin = pkg_dir.join("usr/bin");
+    let mut commands = Vec::<String>::new();
+    if bin.is_dir() {
+        for ent in std::fs::read_dir(&bin)? {
+            commands.push(ent?.file_name().to_string_lossy().to_string());
+        }
+        commands.sort();
+    }
+    Ok(commands)
+}
+
+/// Exports the commands, desktop entries and icons of a package. owners maps
+/// the commands exported so far to their package, to report conflicts.
+fn export_package(
+    pkg: &Package,
+    dpt: &Path,
+    exports: &Path,
+    owners: &mut HashMap<String, String>,
+) -> Result<()> {
+    let pkg_dir = get_package_dir(pkg);
+    let config = read_package_config(&pkg_dir)?;
+    for cmd in get_exported_commands(&config, &pkg_dir)? {
+        if let Some(owner) = owners.get(&cmd) {
+            warn!(
+                "Command `{}` is exported by both {} and {}, keeping {}'s",
+                cmd, owner, pkg.name, owner
+            );
+            continue;
+        }
+        write_wrapper(&exports.join("bin").join(&cmd), dpt, pkg, &cmd)?;
+        owners.insert(cmd, pkg.name.clone());
+    }
+
+    let applications = pkg_dir.join("usr/share/applications");
+    if applications.is_dir() {
+        let commands = get_runnable_commands(&config, &pkg_dir)?;
+        for ent in std::fs::read_dir(&applications)? {
+            let path = ent?.path();
+            if path.extension().is_none_or(|x| x != "desktop") {
+                continue;
+            }
+            let Some(name) = path.file_name() else {
+                continue;
+            };
+            let out = exports.join("share/applications").join(name);
+            if out.symlink_metadata().is_ok() {
+                warn!("{} is already exported, skipping it", out.display());
+                continue;
+            }
+            let entry = std::fs::read_to_string(&path)
+                .context(anyhow!("Failed to read {}", path.display()))?;
+            std::fs::write(
+                out,
+                rewrite_desktop_entry(
+                    &entry, dpt, pkg, exports, &config, &commands,
+                ),
+            )?;
+        }
+    }
+
+    // Icons are linked into the store, keeping their place in the theme
+    for dir in ["share/icons", "share/pixmaps"] {
+        let src = pkg_dir.join("usr").join(dir);
+        for ent in WalkDir::new(&src).into_iter().filter_map(|e| e.ok()) {
+            let out = exports.join(dir).join(ent.path().strip_prefix(&src)?);
+            if ent.file_type().is_dir() {
+                std::fs::DirBuilder::new().recursive(true).create(&out)?;
+            } else if out.symlink_metadata().is_err() {
+                symlink(ent.path(), &out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Regenerates the exports of pkgs. Everything exported before is removed, so
+/// that packages which were removed don't leave anything behind.
+pub fn export_packages(pkgs: &[Package]) -> Result<()> {
+    let exports = get_exports_location();
+    if exports.symlink_metadata().is_ok() {
+        std::fs::remove_dir_all(&exports)?;
+    }
+    for dir in ["bin", "share/applications"] {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .create(exports.join(dir))?;
+    }
+    let dpt =
+        std::env::current_exe().context("Failed to get the location of dpt")?;
+    let mut owners = HashMap::new();
+    for pkg in pkgs {
+        export_package(pkg, &dpt, &exports, &mut owners)
+            .context(anyhow!("Failed to export {}", pkg))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pkg::get_package_config;
+
+    use super::*;
+
+    fn rewrite(entry: &str, config: &str, commands: &[&str]) -> String {
+        let commands: Vec<String> =
+            commands.iter().map(|x| x.to_string()).collect();
+        rewrite_desktop_entry(
+            entry,
+            Path::new("/usr/bin/dpt"),
+            &Package::new("foo".into(), "1.0.0".into()),
+            Path::new("/dpt/exports"),
+            &get_package_config(&format!(
+                "name foo\nversion \"1.0.0\"\n{}",
+                config
+            ))
+            .unwrap(),
+            &commands,
+        )
+    }
+
+    #[test]
+    fn desktop_entry() {
+        let entry = "[Desktop Entry]
+Name=Foo
+TryExec=foo
+Exec=\"/usr/bin/foo bar\" --new-window %U
+Icon=/usr/share/pixmaps/foo.png
+";
+        assert_eq!(
+            rewrite(entry, "", &[]),
+            "[Desktop Entry]
+Name=Foo
+Exec=/usr/bin/dpt run-multi foo-1.0.0 --cmd \"/usr/bin/foo bar\" -- --new-window %U
+Icon=/dpt/exports/share/pixmaps/foo.png
+"
+        );
+    }
+
+    #[test]
+    fn desktop_entry_commands() {
+        let config = "exports { foo \"/usr/lib/foo/foo\"; }";
+        assert_eq!(
+            rewrite("Exec=/usr/lib/foo/foo %U", config, &["foo"]),
+            "Exec=/usr/bin/dpt run-multi foo-1.0.0 --cmd foo -- %U\n"
+        );
+        assert_eq!(
+            rewrite("Exec=foo-helper", config, &["foo", "foo-helper"]),
+            "Exec=/usr/bin/dpt run-multi foo-1.0.0 --cmd foo-helper --\n"
+        );
+        // Not found by `dpt run-multi --cmd`, e.g. in /usr/libexec
+        assert_eq!(
+            rewrite("Exec=foo-daemon --start", config, &["foo"]),
+            "Exec=foo-daemon --start\n"
+        );
+    }
+
+    #[test]
+    fn wrapper_runs_exported_version() {
+        let out = std::env::temp_dir()
+            .join(format!("dpt-wrapper-{}", std::process::id()));
+        write_wrapper(
+            &out,
+            Path::new("/usr/bin/dpt"),
+            &Package::new("foo".into(), "1.2.0".into()),
+            "foo",
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&out).unwrap(),
+            "#!/bin/sh\nexec '/usr/bin/dpt' 'run-multi' 'foo-1.2.0' '--cmd' \
+            'foo' '--' \"$@\"\n"
+        );
+        std::fs::remove_file(out).unwrap();
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(desktop_quote("plain"), "plain");
+        assert_eq!(desktop_quote("a $b"), "\"a \\$b\"");
+    }
+}
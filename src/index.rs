@@ -0,0 +1,793 @@
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use indicatif::ProgressIterator;
+use kdl::{KdlDocument, KdlEntry, KdlNode};
+use log::{info, warn};
+
+use crate::{
+    pkg::{
+        compare_versions, decompress_pkg_read, get_package_config,
+        parse_depends, parse_kdl, PackageConfig,
+    },
+    repo::{
+        get_kdl_string_prop, parse_index_shards, push_onto_url, OnlinePackage,
+    },
+};
+
+/// Directory next to the top-level index that holds the shards of a split
+/// index
+pub const SHARD_DIR: &str = "index";
+
+/// Options controlling how `gen-index` finds and records packages.
+pub struct GenIndexOptions {
+    /// Directory that is searched for `.dpt` files. Paths in the index are
+    /// relative to it.
+    pub root: PathBuf,
+    /// Index file to write. Defaults to `index.kdl` inside of `root`.
+    pub output: Option<PathBuf>,
+    /// Files and directories (relative to `root`) that are not indexed.
+    pub exclude: Vec<glob::Pattern>,
+    /// If set, paths are written as absolute URLs below this one.
+    pub base_url: Option<String>,
+    pub follow_links: bool,
+    /// Also write zstd compressed copies of the index files.
+    pub compress: bool,
+    /// Split the index into one shard per first letter of package names,
+    /// with the output file listing the shards.
+    pub split: bool,
+}
+
+impl GenIndexOptions {
+    pub fn output_path(&self) -> PathBuf {
+        match &self.output {
+            Some(x) => x.clone(),
+            None => self.root.join("index.kdl"),
+        }
+    }
+
+    /// Turns on compression and splitting if the existing index uses them,
+    /// so that maintenance commands keep the layout of the repository. Bails
+    /// if the index has absolute URLs and no base URL is given, as they would
+    /// be lost.
+    pub fn detect_layout(&mut self) -> Result<()> {
+        let out = self.output_path();
+        if compressed_path(&out).is_file() {
+            self.compress = true;
+        }
+        if let Ok(x) = read_local_index_file(&out) {
+            if parse_index_shards(&x).is_ok_and(|x| !x.is_empty()) {
+                self.split = true;
+            }
+        }
+        if self.base_url.is_none() {
+            if let Ok((entries, _)) = read_local_index(&out) {
+                if let Some(x) = entries.iter().find(|x| x.path.contains("://"))
+                {
+                    bail!(
+                        "The index lists {} at {}, pass the --base-url it was generated with!",
+                        x.config.name,
+                        x.path
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for GenIndexOptions {
+    fn default() -> Self {
+        GenIndexOptions {
+            root: PathBuf::from("."),
+            output: None,
+            exclude: Vec::new(),
+            base_url: None,
+            follow_links: true,
+            compress: false,
+            split: false,
+        }
+    }
+}
+
+/// A package in a repository index, along with the modification time and
+/// size of its archive when it was read, to tell whether it changed since.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub path: String,
+    pub mtime: i128,
+    pub size: u64,
+    pub config: PackageConfig,
+}
+
+impl IndexEntry {
+    /// Gets the path written into an index for the entry, which is a URL
+    /// below base_url if it's given.
+    fn index_path(&self, base_url: Option<&str>) -> String {
+        match base_url {
+            Some(x) => push_onto_url(x, &self.path),
+            None => self.path.clone(),
+        }
+    }
+
+    /// Gets the package of the entry as it is listed in an index written
+    /// with base_url, with the path relative to the repository if it's None.
+    pub fn to_online_package(&self, base_url: Option<&str>) -> OnlinePackage {
+        OnlinePackage {
+            name: self.config.name.clone(),
+            version: self.config.version.clone(),
+            url: self.index_path(base_url),
+            depends: self.config.depends.clone(),
+        }
+    }
+}
+
+/// Reads `dpt/pkg.kdl` out of a `.dpt` archive
+pub fn read_package_config_from_archive(path: &Path) -> Result<PackageConfig> {
+    let mut pkg = decompress_pkg_read(fs::File::open(path)?)?;
+    for pkg_ent in pkg.entries()? {
+        let mut pkg_ent = pkg_ent?;
+        if pkg_ent.path()? == Path::new("dpt/pkg.kdl") {
+            let mut buf = String::new();
+            pkg_ent.read_to_string(&mut buf)?;
+            return get_package_config(&buf);
+        }
+    }
+    bail!("{} does not contain dpt/pkg.kdl", path.display())
+}
+
+/// Gets the modification time (in nanoseconds) and size of a file
+fn file_stamp(path: &Path) -> Result<(i128, u64)> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .context("File modification time is before the UNIX epoch")?
+        .as_nanos()
+        .try_into()?;
+    Ok((mtime, metadata.len()))
+}
+
+fn get_kdl_int_prop(prop_name: &str, node: &KdlNode) -> Option<i128> {
+    node.get(prop_name).and_then(|x| x.as_integer())
+}
+
+/// Parses an index file into its entries. Entries without a recorded
+/// modification time or size get zero, so they are always re-read.
+pub fn parse_index_entries(index: &str) -> Result<Vec<IndexEntry>> {
+    let doc = parse_kdl(index)?;
+
+    let mut ret = Vec::<IndexEntry>::new();
+    for pkg in doc.nodes() {
+        if pkg.name().value() != "package" {
+            continue;
+        }
+
+        let depends = match pkg.children() {
+            Some(x) => parse_depends(x)?,
+            None => Vec::new(),
+        };
+        ret.push(IndexEntry {
+            path: get_kdl_string_prop("path", pkg)?,
+            mtime: get_kdl_int_prop("mtime", pkg).unwrap_or(0),
+            size: get_kdl_int_prop("size", pkg)
+                .unwrap_or(0)
+                .try_into()
+                .unwrap_or(0),
+            config: PackageConfig {
+                name: get_kdl_string_prop("name", pkg)?,
+                version: get_kdl_string_prop("version", pkg)?,
+                depends,
+                provides_plugs: Vec::new(),
+                plugs: Vec::new(),
+                exports: Vec::new(),
+                env: Vec::new(),
+                conflicts: Vec::new(),
+                replaces: Vec::new(),
+            },
+        });
+    }
+    Ok(ret)
+}
+
+/// Orders index entries by name and then version, so that indexes diff
+/// cleanly.
+fn compare_entries(a: &IndexEntry, b: &IndexEntry) -> Ordering {
+    a.config
+        .name
+        .cmp(&b.config.name)
+        .then_with(|| compare_versions(&a.config.version, &b.config.version))
+        .then_with(|| a.path.cmp(&b.path))
+}
+
+/// Builds the KDL document of an index file out of its entries. If
+/// `base_url` is given, paths are written as URLs below it.
+pub fn index_to_document(
+    entries: &[IndexEntry],
+    base_url: Option<&str>,
+) -> KdlDocument {
+    let mut doc = KdlDocument::new();
+    for ent in entries {
+        let path = ent.index_path(base_url);
+        let mut node = KdlNode::new("package");
+        node.push(KdlEntry::new_prop("name", ent.config.name.clone()));
+        node.push(KdlEntry::new_prop("version", ent.config.version.clone()));
+        node.push(KdlEntry::new_prop("path", path));
+        node.push(KdlEntry::new_prop("mtime", ent.mtime));
+        node.push(KdlEntry::new_prop("size", ent.size as i128));
+
+        if !ent.config.depends.is_empty() {
+            let children = node.ensure_children();
+            for depend in &ent.config.depends {
+                let mut depend_node = KdlNode::new("depends");
+                depend_node.push(KdlEntry::new(depend.name.clone()));
+                if !depend.version_mask.is_empty() {
+                    depend_node.push(KdlEntry::new_prop(
+                        "version",
+                        depend.version_mask.clone(),
+                    ));
+                }
+                children.nodes_mut().push(depend_node);
+            }
+        }
+        doc.nodes_mut().push(node);
+    }
+    doc.autoformat();
+    doc
+}
+
+/// Writes a file by writing a temporary file next to it and renaming it over
+/// the original, so readers never see a half written file.
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, contents)
+        .context(anyhow!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path)
+        .context(anyhow!("Failed to move {} into place", tmp.display()))?;
+    Ok(())
+}
+
+/// Gets the path of the zstd compressed variant of an index file
+fn compressed_path(path: &Path) -> PathBuf {
+    let mut ret = path.as_os_str().to_owned();
+    ret.push(".zst");
+    PathBuf::from(ret)
+}
+
+/// Removes a file if it exists
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    if path.is_file() {
+        fs::remove_file(path)
+            .context(anyhow!("Failed to remove {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Gets the shard that a package is put in for split indexes
+pub fn shard_name(name: &str) -> String {
+    match name.chars().next() {
+        Some(x) if x.is_ascii_alphanumeric() => {
+            x.to_ascii_lowercase().to_string()
+        }
+        _ => "_".to_string(),
+    }
+}
+
+/// Reads an index file on disk, falling back to its compressed variant
+fn read_local_index_file(path: &Path) -> Result<String> {
+    if path.is_file() {
+        return Ok(fs::read_to_string(path)?);
+    }
+    let compressed = fs::read(compressed_path(path))?;
+    Ok(String::from_utf8(zstd::decode_all(&compressed[..])?)?)
+}
+
+/// Reads the entries of an index on disk, following the shards of a split
+/// index. Also returns the paths of the shards.
+pub fn read_local_index(
+    path: &Path,
+) -> Result<(Vec<IndexEntry>, Vec<PathBuf>)> {
+    let index = read_local_index_file(path)?;
+    let mut entries = parse_index_entries(&index)?;
+    let mut shards = Vec::<PathBuf>::new();
+    let dir = path.parent().unwrap_or(Path::new("."));
+    for shard in parse_index_shards(&index)? {
+        let shard = dir.join(shard);
+        entries
+            .append(&mut parse_index_entries(&read_local_index_file(&shard)?)?);
+        shards.push(shard);
+    }
+    Ok((entries, shards))
+}
+
+/// Reads the index of the repository described by `options`, with paths
+/// relative to the repository root even if the index uses a base URL. Also
+/// returns the paths of the shards.
+fn read_repository_index(
+    options: &GenIndexOptions,
+) -> Result<(Vec<IndexEntry>, Vec<PathBuf>)> {
+    let (mut entries, shards) = read_local_index(&options.output_path())?;
+    if let Some(base_url) = &options.base_url {
+        for ent in entries.iter_mut() {
+            if let Some(rel) = ent.path.strip_prefix(base_url.as_str()) {
+                ent.path = rel.trim_start_matches('/').to_string();
+            }
+        }
+    }
+    Ok((entries, shards))
+}
+
+/// Writes an index file, along with a compressed copy if `compress` is set.
+/// Otherwise a stale compressed copy is removed, as clients prefer it.
+fn write_index_file(
+    path: &Path,
+    doc: &KdlDocument,
+    compress: bool,
+) -> Result<()> {
+    let contents = doc.to_string();
+    write_atomic(path, &contents)?;
+    if compress {
+        write_atomic(
+            &compressed_path(path),
+            zstd::encode_all(contents.as_bytes(), 19)?,
+        )?;
+    } else {
+        remove_file_if_exists(&compressed_path(path))?;
+    }
+    Ok(())
+}
+
+/// Writes the index for `entries` to `out`, split into shards if requested.
+/// Returns the paths of the shards that were written.
+pub fn write_index(
+    out: &Path,
+    entries: &[IndexEntry],
+    options: &GenIndexOptions,
+) -> Result<Vec<PathBuf>> {
+    let base_url = options.base_url.as_deref();
+    if !options.split {
+        write_index_file(
+            out,
+            &index_to_document(entries, base_url),
+            options.compress,
+        )?;
+        return Ok(Vec::new());
+    }
+
+    let mut shards = Vec::<(String, Vec<IndexEntry>)>::new();
+    for ent in entries {
+        let name = shard_name(&ent.config.name);
+        match shards.iter_mut().find(|x| x.0 == name) {
+            Some(x) => x.1.push(ent.clone()),
+            None => shards.push((name, vec![ent.clone()])),
+        }
+    }
+    shards.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let dir = out.parent().unwrap_or(Path::new("."));
+    fs::DirBuilder::new()
+        .recursive(true)
+        .create(dir.join(SHARD_DIR))?;
+
+    let mut manifest = KdlDocument::new();
+    let mut written = Vec::<PathBuf>::new();
+    for (name, shard_entries) in shards {
+        let rel = format!("{}/{}.kdl", SHARD_DIR, name);
+        let path = dir.join(&rel);
+        write_index_file(
+            &path,
+            &index_to_document(&shard_entries, base_url),
+            options.compress,
+        )?;
+        written.push(path);
+
+        let mut node = KdlNode::new("shard");
+        node.push(KdlEntry::new_prop("path", rel));
+        manifest.nodes_mut().push(node);
+    }
+    manifest.autoformat();
+    write_index_file(out, &manifest, options.compress)?;
+    Ok(written)
+}
+
+/// Gets the path of `path` relative to `root` as a string, the way it is
+/// keyed in the index.
+fn relative_path_string(path: &Path, root: &Path) -> Result<String> {
+    let path = path.strip_prefix(root).unwrap_or(path);
+    Ok(path
+        .to_str()
+        .ok_or(anyhow!("Failed to convert file path into a str"))?
+        .to_string())
+}
+
+/// Finds all of the `.dpt` files under the repository root, skipping
+/// anything matching one of the exclusion patterns.
+fn find_dpts(options: &GenIndexOptions) -> Vec<PathBuf> {
+    let root = &options.root;
+    walkdir::WalkDir::new(root)
+        .follow_links(options.follow_links)
+        .into_iter()
+        .filter_entry(|x| {
+            let rel = x.path().strip_prefix(root).unwrap_or(x.path());
+            !options.exclude.iter().any(|p| p.matches_path(rel))
+        })
+        .filter_map(|x| x.ok())
+        .filter(|x| {
+            x.file_type().is_file()
+                && x.path().extension().is_some_and(|x| x == "dpt")
+        })
+        .map(|x| x.path().to_owned())
+        .collect()
+}
+
+/// Finds the packages of a repository, returning their entries along with
+/// the shards of the existing index. Entries of an existing index are reused
+/// for archives that have not changed.
+fn scan_repository(
+    options: &GenIndexOptions,
+) -> Result<(Vec<IndexEntry>, Vec<PathBuf>)> {
+    let out = options.output_path();
+    if let Some(x) = &options.base_url {
+        if !x.contains("://") {
+            bail!("Base URL `{}` is not an absolute URL!", x);
+        }
+    }
+
+    let mut old_entries = HashMap::<String, IndexEntry>::new();
+    let mut old_shards = Vec::<PathBuf>::new();
+    if out.is_file() || compressed_path(&out).is_file() {
+        match read_repository_index(options) {
+            Ok((x, shards)) => {
+                old_shards = shards;
+                for ent in x {
+                    old_entries.insert(ent.path.clone(), ent);
+                }
+            }
+            Err(e) => warn!(
+                "Failed to parse existing index {}, regenerating it: {}",
+                out.display(),
+                e
+            ),
+        }
+    }
+
+    let mut entries = Vec::<Option<IndexEntry>>::new();
+    let mut changed = Vec::<(usize, PathBuf, String, i128, u64)>::new();
+    for ent in find_dpts(options) {
+        let ent_path = relative_path_string(&ent, &options.root)?;
+        let (mtime, size) = file_stamp(&ent)?;
+
+        match old_entries.remove(&ent_path) {
+            Some(x) if x.mtime == mtime && x.size == size => {
+                entries.push(Some(x));
+            }
+            _ => {
+                changed.push((entries.len(), ent, ent_path, mtime, size));
+                entries.push(None);
+            }
+        }
+    }
+
+    for (i, ent, path, mtime, size) in
+        changed.into_iter().progress().with_style(
+            indicatif::ProgressStyle::default_bar()
+                .template(crate::PROGRESS_STYLE)?
+                .progress_chars(crate::PROGRESS_CHARS),
+        )
+    {
+        match read_package_config_from_archive(&ent) {
+            Ok(config) => {
+                entries[i] = Some(IndexEntry {
+                    path,
+                    mtime,
+                    size,
+                    config,
+                })
+            }
+            Err(e) => warn!("Skipping {}: {}", ent.display(), e),
+        }
+    }
+
+    let mut entries =
+        entries.into_iter().flatten().collect::<Vec<IndexEntry>>();
+    entries.sort_by(compare_entries);
+    Ok((entries, old_shards))
+}
+
+/// Writes the index of a repository with entries, removing the shards of the
+/// old index that aren't used anymore.
+fn write_repository_index(
+    options: &GenIndexOptions,
+    entries: &[IndexEntry],
+    old_shards: Vec<PathBuf>,
+) -> Result<()> {
+    let out = options.output_path();
+    let shards = write_index(&out, entries, options)?;
+
+    for shard in old_shards {
+        if !shards.contains(&shard) {
+            remove_file_if_exists(&shard)?;
+            remove_file_if_exists(&compressed_path(&shard))?;
+        }
+    }
+    if !options.split {
+        // Only succeeds if the shard directory is empty
+        let _ = fs::remove_dir(
+            out.parent().unwrap_or(Path::new(".")).join(SHARD_DIR),
+        );
+    }
+    Ok(())
+}
+
+/// Generates the index file for a repository, returning its entries.
+pub fn gen_index(options: &GenIndexOptions) -> Result<Vec<IndexEntry>> {
+    let (entries, old_shards) = scan_repository(options)?;
+    write_repository_index(options, &entries, old_shards)?;
+    Ok(entries)
+}
+
+/// Copies packages into the repository root and adds them to the index,
+/// returning its entries.
+pub fn add_to_repository(
+    options: &GenIndexOptions,
+    files: &[PathBuf],
+) -> Result<Vec<IndexEntry>> {
+    let (mut entries, old_shards) = scan_repository(options)?;
+
+    for file in files {
+        let config = read_package_config_from_archive(file)
+            .context(anyhow!("Failed to read package {}", file.display()))?;
+        let file_name = format!("{}-{}.dpt", config.name, config.version);
+        if let Some(x) = entries.iter().find(|x| {
+            x.config.name == config.name
+                && x.config.version == config.version
+                && x.path != file_name
+        }) {
+            bail!(
+                "{} {} is already in the repository at {}!",
+                config.name,
+                config.version,
+                x.path
+            );
+        }
+
+        let dest = options.root.join(&file_name);
+        if fs::canonicalize(file)? != dest.canonicalize().unwrap_or_default() {
+            fs::copy(file, &dest).context(anyhow!(
+                "Failed to copy {} to {}",
+                file.display(),
+                dest.display()
+            ))?;
+        }
+        info!("Added {} {}", config.name, config.version);
+        let (mtime, size) = file_stamp(&dest)?;
+        entries.retain(|x| x.path != file_name);
+        entries.push(IndexEntry {
+            path: file_name,
+            mtime,
+            size,
+            config,
+        });
+    }
+
+    entries.sort_by(compare_entries);
+    write_repository_index(options, &entries, old_shards)?;
+    Ok(entries)
+}
+
+/// Deletes the files of the given index entries from the repository
+fn delete_entries(
+    options: &GenIndexOptions,
+    entries: &[IndexEntry],
+) -> Result<()> {
+    for ent in entries {
+        let path = options.root.join(&ent.path);
+        remove_file_if_exists(&path)?;
+        info!("Removed {} {}", ent.config.name, ent.config.version);
+    }
+    Ok(())
+}
+
+/// Removes a package from the repository, deleting its files, and returns
+/// the entries of the index. Every version is removed if `version` is None.
+pub fn remove_from_repository(
+    options: &GenIndexOptions,
+    name: &str,
+    version: Option<&str>,
+) -> Result<Vec<IndexEntry>> {
+    let (entries, old_shards) = scan_repository(options)?;
+
+    let (matching, kept): (Vec<IndexEntry>, Vec<IndexEntry>) =
+        entries.into_iter().partition(|x| {
+            x.config.name == name
+                && version.is_none_or(|v| x.config.version == v)
+        });
+    if matching.is_empty() {
+        match version {
+            Some(v) => bail!("{} {} is not in the repository!", name, v),
+            None => bail!("{} is not in the repository!", name),
+        }
+    }
+
+    delete_entries(options, &matching)?;
+    write_repository_index(options, &kept, old_shards)?;
+    Ok(kept)
+}
+
+/// Removes all but the newest `keep` versions of every package in the
+/// repository, deleting their files, and returns the entries of the index.
+pub fn prune_repository(
+    options: &GenIndexOptions,
+    keep: usize,
+) -> Result<Vec<IndexEntry>> {
+    // The entries are sorted, so the newer versions of a package come after
+    let (entries, old_shards) = scan_repository(options)?;
+    let outdated = (0..entries.len())
+        .map(|i| {
+            entries[i + 1..]
+                .iter()
+                .filter(|x| x.config.name == entries[i].config.name)
+                .count()
+                >= keep
+        })
+        .collect::<Vec<bool>>();
+    let (to_delete, kept): (Vec<_>, Vec<_>) =
+        entries.into_iter().zip(outdated).partition(|x| x.1);
+    let to_delete = to_delete.into_iter().map(|x| x.0).collect::<Vec<_>>();
+    let kept = kept.into_iter().map(|x| x.0).collect::<Vec<_>>();
+
+    delete_entries(options, &to_delete)?;
+    write_repository_index(options, &kept, old_shards)?;
+    Ok(kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pkg::Dependency;
+
+    #[test]
+    fn index_round_trip() {
+        let entries = vec![
+            IndexEntry {
+                path: "test.dpt".to_string(),
+                mtime: 1739000000123456789,
+                size: 4096,
+                config: PackageConfig {
+                    name: "test".to_string(),
+                    version: "9.11.14".to_string(),
+                    depends: vec![],
+                    provides_plugs: vec![],
+                    plugs: vec![],
+                    exports: vec![],
+                    env: vec![],
+                    conflicts: vec![],
+                    replaces: vec![],
+                },
+            },
+            IndexEntry {
+                path: "sub/example-1.2.3.dpt".to_string(),
+                mtime: 1,
+                size: 2,
+                config: PackageConfig {
+                    name: "example".to_string(),
+                    version: "1.2.3".to_string(),
+                    depends: vec![
+                        Dependency {
+                            name: "example1".to_string(),
+                            version_mask: "".to_string(),
+                        },
+                        Dependency {
+                            name: "example2".to_string(),
+                            version_mask: ">=10.2.0".to_string(),
+                        },
+                    ],
+                    provides_plugs: vec![],
+                    plugs: vec![],
+                    exports: vec![],
+                    env: vec![],
+                    conflicts: vec![],
+                    replaces: vec![],
+                },
+            },
+        ];
+
+        let parsed =
+            parse_index_entries(&index_to_document(&entries, None).to_string())
+                .unwrap();
+        assert_eq!(parsed, entries);
+    }
+
+    #[test]
+    fn index_escapes_strings() {
+        let entries = vec![IndexEntry {
+            path: "weird \"name\".dpt".to_string(),
+            mtime: 1,
+            size: 2,
+            config: PackageConfig {
+                name: "weird \"name\"".to_string(),
+                version: "1.0".to_string(),
+                depends: vec![],
+                provides_plugs: vec![],
+                plugs: vec![],
+                exports: vec![],
+                env: vec![],
+                conflicts: vec![],
+                replaces: vec![],
+            },
+        }];
+
+        let index = index_to_document(&entries, Some("https://my.repo/dpt"))
+            .to_string();
+        let parsed = parse_index_entries(&index).unwrap();
+        assert_eq!(parsed[0].config.name, "weird \"name\"");
+        assert_eq!(parsed[0].path, "https://my.repo/dpt/weird \"name\".dpt");
+    }
+
+    #[test]
+    fn shard_names() {
+        assert_eq!(shard_name("Bash"), "b");
+        assert_eq!(shard_name("7zip"), "7");
+        assert_eq!(shard_name("_private"), "_");
+        assert_eq!(shard_name(""), "_");
+    }
+
+    #[test]
+    fn entries_are_sorted() {
+        let entry = |name: &str, version: &str| IndexEntry {
+            path: format!("{}-{}.dpt", name, version),
+            mtime: 0,
+            size: 0,
+            config: PackageConfig {
+                name: name.to_string(),
+                version: version.to_string(),
+                depends: vec![],
+                provides_plugs: vec![],
+                plugs: vec![],
+                exports: vec![],
+                env: vec![],
+                conflicts: vec![],
+                replaces: vec![],
+            },
+        };
+        let mut entries = [
+            entry("zlib", "1.3.1"),
+            entry("bash", "5.10.0"),
+            entry("bash", "5.2.0"),
+            entry("coreutils", "9.5"),
+        ];
+        entries.sort_by(compare_entries);
+        assert_eq!(
+            entries
+                .iter()
+                .map(|x| x.path.as_str())
+                .collect::<Vec<&str>>(),
+            vec![
+                "bash-5.2.0.dpt",
+                "bash-5.10.0.dpt",
+                "coreutils-9.5.dpt",
+                "zlib-1.3.1.dpt"
+            ]
+        );
+    }
+
+    #[test]
+    fn index_without_stamps() {
+        let parsed = parse_index_entries(
+            r#"package name=test version="1.0.0" path="/test.dpt""#,
+        )
+        .unwrap();
+        assert_eq!(parsed[0].mtime, 0);
+        assert_eq!(parsed[0].size, 0);
+        assert_eq!(parsed[0].path, "/test.dpt");
+    }
+}
@@ -5,14 +5,27 @@
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 mod base;
+mod cache;
+mod cli;
 mod config;
+mod conflicts;
 mod dpt_file;
 mod env;
+mod exec;
+mod exports;
 mod gen_pkg;
+mod index;
 mod pkg;
+mod plug;
+mod query;
+mod rebuild;
 mod repo;
 mod run;
+mod sandbox;
+mod shell;
 mod store;
+mod userns;
+mod verify;
 
 pub const PROGRESS_STYLE_BYTES: &str =
     "{msg} [{wide_bar:.green/blue}] {bytes}/{total_bytes} ({eta})";
@@ -21,36 +34,45 @@ pub const PROGRESS_STYLE: &str =
 pub const PROGRESS_CHARS: &str = "##-";
 
 use std::{
-    fs::write,
-    io::Read,
-    path::{Path, PathBuf},
+    io::{ErrorKind, Write},
     process::exit,
-    str::FromStr,
-    sync::{Arc, Mutex},
 };
 
-use base::rebuild_base;
-use dpt_file::read_dpt_file;
-use indicatif::ProgressIterator;
+use dpt_file::{
+    add_package_to_dpt_document, read_dpt_file, read_dpt_file_document,
+    remove_package_from_dpt_document, write_dpt_file_document,
+};
 
 use anyhow::{anyhow, Context, Result};
-use colog::format::CologStyle;
-use kdl::{KdlDocument, KdlEntry, KdlNode, KdlValue};
-use log::{error, warn, Level};
-use pkg::{
-    decompress_pkg_read, get_package_config, string_to_package, Package,
+use clap::{CommandFactory, Parser};
+use clap_complete::{
+    env::{Bash, EnvCompleter, Fish, Zsh},
+    CompleteEnv,
 };
+use cli::{Cli, Command, CompletionShell, RepoCommand, StoreCommand};
+use colog::format::CologStyle;
+use index::{GenIndexOptions, IndexEntry};
+use log::{error, info, warn, Level};
+use pkg::{string_to_package, Package};
+use rebuild::rebuild;
 use repo::{
-    get_all_available_packages, install_pkg_and_dependencies,
-    newest_package_from_name, package_to_onlinepackage, InstallResult,
+    get_all_available_packages, install_pkg, install_pkg_and_dependencies,
+    newest_package_from_name, package_to_onlinepackage,
+    resolve_dependencies_for_package, InstallResult, InstallSummary,
     OnlinePackage,
 };
 use run::run_multiple_packages;
-use store::{get_dpt_dir, get_installed_packages};
+use serde::Serialize;
+use shell::run_shell;
+use store::{
+    get_installed_packages, get_installed_packages_without_dpt_file,
+    get_package_dir, lock_package_dir, store_is_immutable, unlock_package_dir,
+};
 use uzers::{
-    self, get_current_uid, get_effective_uid,
+    self, get_current_gid, get_current_uid, get_effective_uid,
     switch::{set_current_uid, set_effective_uid},
 };
+use verify::{record_manifest, verify_package, Verification};
 
 pub struct CustomLevelToken;
 
@@ -68,6 +90,10 @@ impl CologStyle for CustomLevelToken {
 }
 
 fn main() -> Result<()> {
+    // Answers the shell when it asks for completions, before anything else
+    // gets printed
+    CompleteEnv::with_factory(Cli::command).complete();
+
     let mut builder = colog::basic_builder();
     builder.format(colog::formatter(CustomLevelToken));
     if cfg!(debug_assertions) {
@@ -79,182 +105,154 @@ fn main() -> Result<()> {
     builder.filter(Some("reqwest"), log::LevelFilter::Warn);
     builder.init();
 
-    let args = std::env::args().collect::<Vec<String>>();
-    let argc = std::env::args().count();
-
-    if argc < 2 {
-        error!("Not enough arguments!");
-        print_help();
-        exit(exitcode::USAGE);
-    }
+    let cli = match Cli::try_parse() {
+        Ok(x) => x,
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            let _ = e.print();
+            exit(exitcode::USAGE);
+        }
+    };
 
-    if get_effective_uid() != 0 {
+    // Running packages works without root as long as user namespaces do
+    if get_effective_uid() != 0
+        && !matches!(
+            cli.command,
+            Command::Completions { .. }
+                | Command::List { .. }
+                | Command::Why { .. }
+                | Command::Rdepends { .. }
+                | Command::Graph { .. }
+                | Command::Verify { repair: false, .. }
+                | Command::Search { .. }
+                | Command::Info { .. }
+        )
+        && !(matches!(
+            cli.command,
+            Command::Run { .. }
+                | Command::RunMulti { .. }
+                | Command::Shell { .. }
+        ) && userns::user_namespaces_available(
+            get_current_uid(),
+            get_current_gid(),
+        ))
+    {
         error!("FPKG needs to be installed setuid or run as root!");
         exit(exitcode::USAGE);
     }
 
-    if args[1] != "chroot-not-intended-for-interactive-use"
-        && args[1] != "run"
-        && args[1] != "run-multi"
-        && args[1] != "dev-env"
-    {
-        for arg in &args {
-            match arg.as_str() {
-                "--help" | "-h" => {
-                    print_help();
-                    return Ok(());
-                }
-                "--version" | "-v" => {
-                    println!("{}", env!("CARGO_PKG_VERSION"));
-                    return Ok(());
-                }
-                _ => {} // It will just be handled as a positional argument
-            }
-        }
-    }
-
-    match &args.get(1).unwrap() as &str {
-        "gen-pkg" => {
+    match cli.command {
+        Command::GenPkg { directory, output } => {
             // You are allowed to generate a package as non-root
             set_effective_uid(get_current_uid())?;
-            if argc < 3 {
-                error!("Not enough arguments!");
-                exit(exitcode::USAGE);
-            }
-
-            let path = PathBuf::from(&format!("{}", &args[2]));
-            let mut out = path.with_extension("dpt");
-
-            if argc > 3 {
-                out = PathBuf::from_str(&args[3])?;
-            }
-            let err = gen_pkg::gen_pkg(&path, &out);
+            let out = output.unwrap_or_else(|| directory.with_extension("dpt"));
+            let err = gen_pkg::gen_pkg(&directory, &out);
             if let Err(e) = err {
                 error!("{}", e);
                 exit(1);
             }
         }
-        "rebuild" => {
+        Command::Rebuild { dry_run } => {
+            command_requires_root_uid();
+            print_install_summary(&rebuild(dry_run)?, dry_run, cli.json)?;
+        }
+        Command::Add {
+            rebuild: rebuild_after,
+            packages: names,
+        } => {
             command_requires_root_uid();
-            let dpt = read_dpt_file()?;
-            let mut done_list: Vec<(OnlinePackage, InstallResult)> = Vec::new();
+            let mut doc = read_dpt_file_document()?;
             let repo_packages = get_all_available_packages()?;
-
-            for package in dpt.packages.iter() {
-                install_pkg_and_dependencies(
-                    &newest_package_from_name(&package.name, &repo_packages)
-                        .context(anyhow!(
-                            "Package {} is not found in repository!",
-                            package
-                        ))?,
-                    &repo_packages,
-                    &mut done_list,
-                    false,
-                )?;
+            for name in names {
+                let pkg = match string_to_package(&name) {
+                    Ok(x)
+                        if package_to_onlinepackage(&x, &repo_packages)
+                            .is_ok() =>
+                    {
+                        x
+                    }
+                    _ => {
+                        newest_package_from_name(&name, &repo_packages)
+                            .context(anyhow!(
+                                "Package `{}` is not found in repository!",
+                                name
+                            ))?;
+                        Package::new(name.clone(), String::new())
+                    }
+                };
+                if !add_package_to_dpt_document(&mut doc, &pkg) {
+                    warn!(
+                        "Package `{}` is already in the dpt file, leaving it as it is",
+                        name
+                    );
+                }
             }
+            write_dpt_file_document(&doc)?;
 
-            rebuild_base(&dpt).context("Failed to build base!")?;
-
-            let mut dpt_lock = KdlDocument::new();
-
-            let mut packages_node = KdlNode::new("packages");
-            let mut packages_doc = KdlDocument::new();
-
-            let done_list = remove_duplicates(done_list);
-            for x in done_list {
-                let mut node = KdlNode::new(x.0.name);
-                node.entries_mut()
-                    .push(KdlEntry::new(KdlValue::String(x.0.version)));
-                packages_doc.nodes_mut().push(node);
+            if rebuild_after {
+                print_install_summary(&rebuild(false)?, false, cli.json)?;
             }
-
-            packages_node.set_children(packages_doc);
-            dpt_lock.nodes_mut().push(packages_node);
-
-            write(get_dpt_dir().join("dpt.lock"), dpt_lock.to_string())
-                .context("Failed to write dpt.lock file")?;
         }
-        "run" => {
-            if argc < 3 {
-                error!("Not enough arguments!");
-                exit(exitcode::USAGE);
+        Command::Remove {
+            rebuild: rebuild_after,
+            packages: names,
+        } => {
+            command_requires_root_uid();
+            let mut doc = read_dpt_file_document()?;
+            for name in names {
+                if !remove_package_from_dpt_document(&mut doc, &name) {
+                    warn!("Package `{}` is not in the dpt file", name);
+                }
+            }
+            write_dpt_file_document(&doc)?;
+
+            if rebuild_after {
+                print_install_summary(&rebuild(false)?, false, cli.json)?;
             }
-            let pkg =
-                friendly_str_to_package(&args[2], &get_installed_packages()?)?;
+        }
+        Command::Run {
+            options,
+            mut command,
+        } => {
+            let args = command.split_off(1);
+            let pkg = friendly_str_to_package(
+                &command[0],
+                &get_installed_packages()?,
+            )?;
             let uid = get_current_uid();
             if uid == 0 && std::env::var("SUDO_USER").is_ok() {
                 warn!("When running `dpt run` using sudo, the inner package gets run as root. Use setuid instead of sudo to run it as yourself");
             }
-            set_current_uid(0)?;
-            let mut run_args = Vec::<String>::new();
-            if argc > 3 {
-                for arg in &args[3..] {
-                    run_args.push(arg.clone());
-                }
-            }
-            exit(run::run_pkg(&pkg, uid, run_args, None, false)?);
+            let options = options.into_options();
+            exit(run::run_pkg(&pkg, uid, args, None, &options, false)?);
         }
-        "run-multi" => {
-            if argc < 3 {
-                error!("Not enough arguments!");
-                exit(exitcode::USAGE);
-            }
+        Command::RunMulti {
+            cmd,
+            options,
+            packages: names,
+            args,
+        } => {
             let packages = get_installed_packages()?;
-            let mut packages_to_run = Vec::<Package>::new();
-            let mut previous_was_cmd = false;
-            let mut cmd: Option<&str> = None;
-            for pkg in &args[2..] {
-                if pkg == "--" {
-                    break;
-                }
-                if previous_was_cmd {
-                    cmd = Some(pkg);
-                    continue;
-                }
-                if pkg == "--cmd" || pkg == "-c" {
-                    previous_was_cmd = true;
-                    continue;
-                } else {
-                    previous_was_cmd = false;
-                }
-
-                let version = friendly_str_to_package(pkg, &packages)
-                    .context(anyhow!("Package `{}` not found!", pkg))?;
-                packages_to_run.push(version);
-            }
+            let packages_to_run = find_packages(&names, &packages)?;
             let uid = get_current_uid();
             if uid == 0 && std::env::var("SUDO_USER").is_ok() {
                 warn!("When running `dpt run` using sudo, the inner package gets run as root. Use setuid instead of sudo to run it as yourself");
             }
-            set_current_uid(0)?;
-
-            let mut run_args = Vec::<String>::new();
-            if argc > 3 {
-                let mut active = false;
-                for arg in &args[3..] {
-                    if active {
-                        run_args.push(arg.clone());
-                    } else {
-                        if arg == "--" {
-                            active = true;
-                        }
-                    }
-                }
-            }
             exit(run_multiple_packages(
                 &packages_to_run,
                 uid,
-                run_args,
-                cmd,
+                args,
+                cmd.cmd.as_deref(),
+                &options.into_options(),
                 false,
             )?);
         }
-        "dev-env" => {
-            if argc < 3 {
-                error!("Not enough arguments!");
-                exit(exitcode::USAGE);
-            }
-
+        Command::DevEnv {
+            cmd,
+            options,
+            packages: names,
+            args,
+        } => {
             let uid = get_current_uid();
             if uid == 0 && std::env::var("SUDO_USER").is_ok() {
                 warn!("When running `dpt dev-env` using sudo, the inner package gets run as root. Use setuid instead of sudo to run it as yourself");
@@ -262,198 +260,358 @@ fn main() -> Result<()> {
             set_current_uid(0)?;
 
             let packages = get_all_available_packages()?;
-            let mut packages_to_run = Vec::<Package>::new();
-            let mut previous_was_cmd = false;
-            let mut cmd: Option<&str> = None;
-            for pkg in &args[2..] {
-                if pkg == "--" {
-                    break;
-                }
-                if previous_was_cmd {
-                    cmd = Some(pkg);
-                    continue;
+            let packages_to_run = find_packages(&names, &packages)?;
+            install_missing_packages(&packages_to_run, &packages)?;
+            exit(run_multiple_packages(
+                &packages_to_run,
+                uid,
+                args,
+                cmd.cmd.as_deref(),
+                &options.into_options(),
+                true,
+            )?);
+        }
+        Command::Shell {
+            dev,
+            options,
+            packages: names,
+            args,
+        } => {
+            let uid = get_current_uid();
+            if uid == 0 && std::env::var("SUDO_USER").is_ok() {
+                warn!("When running `dpt shell` using sudo, the shell gets run as root. Use setuid instead of sudo to run it as yourself");
+            }
+            let packages = if dev {
+                set_current_uid(0)?;
+                get_all_available_packages()?
+            } else {
+                get_installed_packages()?
+            };
+            let packages_to_run = find_packages(&names, &packages)?;
+            if dev {
+                install_missing_packages(&packages_to_run, &packages)?;
+            }
+            exit(run_shell(
+                &packages_to_run,
+                uid,
+                args,
+                options.into_options(),
+                dev,
+            )?);
+        }
+        Command::List { filter } => {
+            let available = match filter.upgradable {
+                true => Some(get_all_available_packages()?),
+                false => None,
+            };
+            let mut pkgs = query::list_store(available.as_ref())?;
+            pkgs.retain(|x| {
+                (!filter.locked || x.locked)
+                    && (!filter.orphans || !x.locked)
+                    && (!filter.upgradable || x.upgrade.is_some())
+            });
+            if cli.json {
+                print_json(&pkgs)?;
+            } else {
+                for pkg in pkgs {
+                    println!("{}", pkg);
                 }
-                if pkg == "--cmd" || pkg == "-c" {
-                    previous_was_cmd = true;
-                    continue;
-                } else {
-                    previous_was_cmd = false;
+            }
+        }
+        Command::Why { package } => {
+            let paths = query::dependency_paths(
+                &read_dpt_file()?.packages,
+                &package,
+                &get_installed_packages()?,
+            )?;
+            if cli.json {
+                print_json(&paths)?;
+            } else if paths.is_empty() {
+                info!("No package of the dpt file depends on {}", package);
+            } else {
+                for path in paths {
+                    let path = path
+                        .iter()
+                        .map(|x| format!("{} {}", x.name, x.version))
+                        .collect::<Vec<String>>();
+                    println!("{}", path.join(" -> "));
                 }
-
-                let version = friendly_str_to_package(pkg, &packages)
-                    .context(anyhow!("Package `{}` not found!", pkg))?;
-                packages_to_run.push(version);
             }
-
-            let mut done_list: Vec<(OnlinePackage, InstallResult)> = Vec::new();
-
-            for package in packages_to_run.iter() {
-                install_pkg_and_dependencies(
-                    &package_to_onlinepackage(&package, &packages)?,
-                    &packages,
-                    &mut done_list,
-                    false,
-                )?;
+        }
+        Command::Rdepends { all, package } => {
+            let packages = match all {
+                true => get_all_available_packages()?,
+                false => get_installed_packages()?,
+            };
+            let found = query::reverse_dependencies(&package, &packages)?;
+            if cli.json {
+                print_json(&found)?;
+            } else if found.is_empty() {
+                info!("No package depends on {}", package);
+            } else {
+                for x in found {
+                    let transitive =
+                        if x.direct { "" } else { " (transitive)" };
+                    println!(
+                        "{} {}{}",
+                        x.package.name, x.package.version, transitive
+                    );
+                }
             }
-
-            let mut run_args = Vec::<String>::new();
-            if argc > 3 {
-                let mut active = false;
-                for arg in &args[3..] {
-                    if active {
-                        run_args.push(arg.clone());
-                    } else {
-                        if arg == "--" {
-                            active = true;
-                        }
-                    }
+        }
+        Command::Graph { package } => {
+            let packages = match package {
+                Some(name) => {
+                    let available = get_all_available_packages()?;
+                    let pkg = friendly_str_to_package(&name, &available)
+                        .context(anyhow!("Package `{}` not found!", name))?;
+                    resolve_dependencies_for_package(&available, &pkg)?
                 }
+                None => get_installed_packages()?,
+            };
+            let graph = query::DependencyGraph::new(packages);
+            if cli.json {
+                print_json(&graph)?;
+            } else {
+                print!("{}", graph.to_dot());
             }
-            exit(run_multiple_packages(
-                &packages_to_run,
-                uid,
-                run_args,
-                cmd,
-                true,
-            )?);
         }
-        "gen-index" => {
-            set_effective_uid(get_current_uid())?;
-            let mut out_str = String::new();
-
-            let dpts = walkdir::WalkDir::new(".")
-                .follow_links(true)
-                .into_iter()
-                .filter(|x| {
-                    x.is_ok()
-                        && x.as_ref().unwrap().path().extension().is_some()
-                        && x.as_ref()
-                            .unwrap()
-                            .path()
-                            .extension()
-                            .unwrap()
-                            .to_str()
-                            == "dpt".into()
-                })
-                .map(|x| x.unwrap().path().to_owned())
-                .collect::<Vec<PathBuf>>();
-            for ent in dpts.into_iter().progress().with_style(
-                indicatif::ProgressStyle::default_bar()
-                    .template(PROGRESS_STYLE)?
-                    .progress_chars(PROGRESS_CHARS),
-            ) {
-                let mut pkg = decompress_pkg_read(std::fs::File::open(&ent)?)?;
-                for pkg_ent in pkg.entries()? {
-                    let mut pkg_ent = pkg_ent?;
-                    if pkg_ent.path()? == Path::new("dpt/pkg.kdl") {
-                        let mut buf = String::new();
-                        pkg_ent.read_to_string(&mut buf)?;
-                        let cfg = get_package_config(&buf)?;
-
-                        let ent_path = match ent.strip_prefix("./") {
-                            Ok(x) => x,
-                            Err(_) => &ent,
-                        };
-                        let ent_path = ent_path
-                            .to_str()
-                            .ok_or(anyhow!(
-                                "Failed to convert file path into a str"
-                            ))?
-                            .to_string();
-
-                        out_str.push_str(&format!(
-                            "package name=\"{}\" version=\"{}\" path=\"{}\"",
-                            cfg.name.clone(),
-                            cfg.version.clone(),
-                            ent_path
-                        ));
-
-                        if cfg.depends.is_empty() {
-                            out_str.push('\n');
-                        } else {
-                            // We have dependencies! Yay!
-                            out_str.push_str(&format!(" {{\n"));
-                            for depend in cfg.depends {
-                                out_str.push_str(&format!(
-                                    "    depends \"{}\"{}\n",
-                                    depend.name,
-                                    if &depend.version_mask != "" {
-                                        format!(
-                                            " version=\"{}\"",
-                                            depend.version_mask
-                                        )
-                                    } else {
-                                        "".to_string()
-                                    }
-                                ));
-                            }
-                            out_str.push_str("}\n");
-                        }
-                    }
+        Command::Verify { package, repair } => {
+            if repair {
+                command_requires_root_uid();
+            }
+            let mut packages = get_installed_packages_without_dpt_file()?;
+            if let Some(name) = package {
+                let found = query::find_packages(&name, &packages)?;
+                packages =
+                    found.into_iter().map(|x| packages[x].clone()).collect();
+            }
+            let mut results = Vec::<Verification>::new();
+            for pkg in packages {
+                results.push(verify_package(&pkg.to_package())?);
+            }
+            results.sort_by(|a, b| a.package.name.cmp(&b.package.name));
+            if cli.json {
+                print_json(&results)?;
+            } else {
+                for x in &results {
+                    println!("{}", x);
                 }
             }
 
-            std::fs::write("index.kdl", &out_str)?;
+            let broken = results
+                .iter()
+                .filter(|x| !x.is_ok())
+                .map(|x| &x.package)
+                .collect::<Vec<&Package>>();
+            if !repair {
+                if !broken.is_empty() {
+                    exit(1);
+                }
+                return Ok(());
+            }
+            // Packages installed before manifests were recorded are taken
+            // as they are
+            for x in results.iter().filter(|x| !x.manifest) {
+                record_manifest(&x.package)?;
+                info!(
+                    "Recorded the manifest of {} {}",
+                    x.package.name, x.package.version
+                );
+            }
+            let available = get_all_available_packages()?;
+            for pkg in broken {
+                let online = package_to_onlinepackage(pkg, &available)
+                    .context(anyhow!(
+                        "{} {} is not in the repositories to repair it from!",
+                        pkg.name,
+                        pkg.version
+                    ))?;
+                install_pkg(&online, true)?;
+                info!("Repaired {} {}", pkg.name, pkg.version);
+            }
         }
-        "chroot-not-intended-for-interactive-use" => {
+        Command::Store { command } => {
             command_requires_root_uid();
-            if argc < 5 {
-                error!("Not enough arguments!");
-                exit(exitcode::USAGE);
+            let (package, lock) = match command {
+                StoreCommand::Unlock { package } => (package, false),
+                StoreCommand::Lock { package } => (package, true),
+            };
+            let mut packages = get_installed_packages_without_dpt_file()?;
+            if let Some(name) = package {
+                let found = query::find_packages(&name, &packages)?;
+                packages =
+                    found.into_iter().map(|x| packages[x].clone()).collect();
             }
-            let uid: u32 = args[3].parse()?;
-            let prev_dir =
-                std::env::current_dir().unwrap_or(PathBuf::from_str("/")?);
-            std::env::set_current_dir(&args[2])?;
-            std::os::unix::fs::chroot(".")?;
-
-            if prev_dir.is_dir() {
-                std::env::set_current_dir(prev_dir)?;
+            let immutable = store_is_immutable()?;
+            for pkg in packages {
+                let dir = get_package_dir(&pkg.clone().to_package());
+                match lock {
+                    true => lock_package_dir(&dir, immutable)?,
+                    false => unlock_package_dir(&dir)?,
+                }
+            }
+        }
+        Command::Search { term } => {
+            let found = query::search(&term, &get_all_available_packages()?);
+            if cli.json {
+                print_json(&found)?;
             } else {
-                std::env::set_current_dir("/")?;
+                for pkg in found {
+                    println!("{} {}", pkg.name, pkg.version);
+                }
             }
-            set_current_uid(uid)?;
+        }
+        Command::Info { package } => {
+            let packages = get_all_available_packages()?;
+            let pkg = friendly_str_to_package(&package, &packages)
+                .context(anyhow!("Package `{}` not found!", package))?;
+            let info = query::package_info(&pkg, &packages)?;
+            if cli.json {
+                print_json(&info)?;
+            } else {
+                println!("{}", info);
+            }
+        }
+        Command::GenIndex { index } => {
+            set_effective_uid(get_current_uid())?;
+            let options = index.into_options();
+            let entries = index::gen_index(&options)?;
+            if cli.json {
+                print_index(&entries, &options)?;
+            }
+        }
+        Command::Repo { command, index } => {
             set_effective_uid(get_current_uid())?;
-            let mut p = std::process::Command::new(&args[4]);
-            if argc > 5 {
-                for a in &args[5..] {
-                    p.arg(a);
+            let mut options = index.into_options();
+            options.detect_layout()?;
+            let entries = match command {
+                RepoCommand::Add { files } => {
+                    index::add_to_repository(&options, &files)?
+                }
+                RepoCommand::Remove { name, version } => {
+                    index::remove_from_repository(
+                        &options,
+                        &name,
+                        version.as_deref(),
+                    )?
                 }
+                RepoCommand::Prune { keep } => {
+                    index::prune_repository(&options, keep.get())?
+                }
+            };
+            if cli.json {
+                print_index(&entries, &options)?;
             }
-            let p: Arc<Mutex<std::process::Child>> =
-                Arc::new(Mutex::new(p.spawn()?));
+        }
+        Command::Completions { shell } => print_completions(shell)?,
+    }
 
-            let proc_arc_clone = Arc::clone(&p);
-            ctrlc::set_handler(move || {
-                let l = proc_arc_clone.lock();
-                if let Ok(mut x) = l {
-                    let _ = x.kill();
-                };
-            })
-            .context("Failed to register ctrlc singal handler")?;
-            let l = p.lock();
-            let exit_code = if let Ok(mut x) = l {
-                x.wait()?.code().unwrap_or(89)
-            } else {
-                243
-            };
+    Ok(())
+}
 
-            exit(exit_code);
-        }
-        cmd => {
-            error!("Unknown command {}!", cmd);
-            print_help();
-            exit(exitcode::USAGE);
-        }
+/// Prints value as JSON, for `--json`. Readers that stop early, like
+/// `head`, aren't an error.
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    let json = serde_json::to_string_pretty(value)?;
+    match writeln!(std::io::stdout(), "{}", json) {
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => Err(e.into()),
+        _ => Ok(()),
     }
+}
 
+/// Prints the packages of an index written with options.
+fn print_index(
+    entries: &[IndexEntry],
+    options: &GenIndexOptions,
+) -> Result<()> {
+    print_json(
+        &entries
+            .iter()
+            .map(|x| x.to_online_package(options.base_url.as_deref()))
+            .collect::<Vec<OnlinePackage>>(),
+    )
+}
+
+/// Prints what rebuilding did to the packages of the system, or would do
+/// with dry_run.
+fn print_install_summary(
+    done: &[(OnlinePackage, InstallResult)],
+    dry_run: bool,
+    json: bool,
+) -> Result<()> {
+    if json {
+        return print_json(
+            &done
+                .iter()
+                .map(|(package, result)| InstallSummary { package, result })
+                .collect::<Vec<InstallSummary>>(),
+        );
+    }
+    let installed = done
+        .iter()
+        .filter(|x| x.1 == InstallResult::Installed)
+        .map(|x| format!("{} {}", x.0.name, x.0.version))
+        .collect::<Vec<String>>();
+    match (dry_run, installed.is_empty()) {
+        (true, true) => info!("Nothing would be installed"),
+        (true, false) => info!("Would install {}", installed.join(", ")),
+        (false, true) => info!("Nothing new was installed"),
+        (false, false) => info!("Installed {}", installed.join(", ")),
+    }
     Ok(())
 }
 
-fn remove_duplicates<T: Eq + std::hash::Hash + Clone>(mut l: Vec<T>) -> Vec<T> {
-    let mut seen = std::collections::HashSet::new();
-    l.retain(|c| seen.insert(c.clone()));
-    l
+/// Prints the script registering the completions of dpt for shell. The
+/// completions themselves are generated by dpt when the shell asks for them.
+fn print_completions(shell: CompletionShell) -> Result<()> {
+    let completer: &dyn EnvCompleter = match shell {
+        CompletionShell::Bash => &Bash,
+        CompletionShell::Zsh => &Zsh,
+        CompletionShell::Fish => &Fish,
+    };
+    let dpt =
+        std::env::current_exe().context("Failed to get the location of dpt")?;
+    completer.write_registration(
+        "COMPLETE",
+        "dpt",
+        "dpt",
+        &dpt.to_string_lossy(),
+        &mut std::io::stdout(),
+    )?;
+    Ok(())
+}
+
+/// Finds the packages named in names, which may include versions.
+fn find_packages(
+    names: &[String],
+    pkgs: &Vec<OnlinePackage>,
+) -> Result<Vec<Package>> {
+    names
+        .iter()
+        .map(|x| {
+            friendly_str_to_package(x, pkgs)
+                .context(anyhow!("Package `{}` not found!", x))
+        })
+        .collect()
+}
+
+/// Installs pkgs and their dependencies into the store when they aren't in
+/// it yet, without adding them to the dpt file.
+fn install_missing_packages(
+    pkgs: &[Package],
+    available: &Vec<OnlinePackage>,
+) -> Result<()> {
+    let mut done_list: Vec<(OnlinePackage, InstallResult)> = Vec::new();
+    for package in pkgs {
+        install_pkg_and_dependencies(
+            &package_to_onlinepackage(package, available)?,
+            available,
+            &mut done_list,
+            false,
+        )?;
+    }
+    Ok(())
 }
 
 fn friendly_str_to_package(
@@ -479,18 +637,3 @@ fn command_requires_root_uid() {
         exit(exitcode::USAGE);
     }
 }
-
-fn print_help() {
-    println!(
-        "Usage: dpt command [additional arguments]
-
-Dpt, package management, done right.
-
-Commands:
-    rebuild         Rebuilds the environment according to the dpt file.
-    run             Runs a program
-    run-multi       Runs the first program specified in an env with the rest
-    gen-pkg         Generates a package from a directory
-    gen-index       Generates the index file for a package repository at PWD"
-    );
-}
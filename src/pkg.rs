@@ -1,20 +1,23 @@
 use anyhow::{anyhow, bail, Context, Result};
-use kdl::{KdlDocument, KdlError, KdlIdentifier};
+use kdl::{KdlDocument, KdlError, KdlIdentifier, KdlNode};
+use serde::Serialize;
 use std::{
     cmp::Ordering,
     fmt::{self, Display},
     io::BufRead,
-    path::Path,
+    path::{Component, Path, PathBuf},
 };
 use tar::Archive;
 
-#[derive(Debug, Clone, Hash, Eq)]
+#[derive(Debug, Clone, Hash, Eq, Serialize)]
 pub struct Dependency {
     pub name: String,
+    /// Empty when any version will do.
+    #[serde(rename = "version")]
     pub version_mask: String,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct Package {
     pub name: String,
     pub version: String,
@@ -38,11 +41,40 @@ impl PartialEq for Dependency {
     }
 }
 
-#[derive(Debug)]
+/// Files or sockets that a package shares with others under a name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProvidedPlug {
+    pub name: String,
+    /// Absolute paths inside of the environment.
+    pub paths: Vec<PathBuf>,
+}
+
+/// A command that a package makes available to `dpt run` and to the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Export {
+    pub name: String,
+    /// The absolute path of the executable inside of the environment.
+    pub path: PathBuf,
+    /// Arguments passed before the ones given when running it.
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone)]
 pub struct PackageConfig {
     pub name: String,
     pub version: String,
     pub depends: Vec<Dependency>,
+    pub provides_plugs: Vec<ProvidedPlug>,
+    /// The names of the plugs the package plugs into.
+    pub plugs: Vec<String>,
+    pub exports: Vec<Export>,
+    /// Environment variables set when running the package.
+    pub env: Vec<(String, String)>,
+    /// Packages that can't be in the same environment as this one.
+    pub conflicts: Vec<String>,
+    /// Packages whose files this one's are meant to take the place of.
+    pub replaces: Vec<String>,
 }
 
 impl PartialEq for PackageConfig {
@@ -50,6 +82,12 @@ impl PartialEq for PackageConfig {
         self.name == other.name
             && self.version == other.version
             && self.depends == other.depends
+            && self.provides_plugs == other.provides_plugs
+            && self.plugs == other.plugs
+            && self.exports == other.exports
+            && self.env == other.env
+            && self.conflicts == other.conflicts
+            && self.replaces == other.replaces
     }
 }
 
@@ -90,6 +128,19 @@ impl Version {
     }
 }
 
+/// Orders version strings from oldest to newest, with the ones that aren't
+/// valid versions after all of the others, by string. Unlike the order of
+/// [`Version`], where `1.2` is equal to `1.2.3`, this is a total order, so
+/// that it can be used to sort.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    match (Version::from_str(a), Version::from_str(b)) {
+        (Ok(x), Ok(y)) => x.n.cmp(&y.n).then_with(|| a.cmp(b)),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
 impl Ord for Version {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         let mut i = 0;
@@ -188,10 +239,25 @@ pub fn get_package_config(file: &str) -> Result<PackageConfig> {
     let version = get_kdl_value_string(&doc, "version")?;
 
     let depends = parse_depends(&doc)?;
+    let provides_plugs = parse_provided_plugs(&doc)?;
+    let plugs = parse_plugs(&doc)?;
+    let exports = parse_exports(&doc)?;
+    let mut env = Vec::<(String, String)>::new();
+    for node in doc.nodes().iter().filter(|x| x.name().value() == "env") {
+        env.extend(parse_env(node)?);
+    }
+    let conflicts = parse_package_names(&doc, "conflicts")?;
+    let replaces = parse_package_names(&doc, "replaces")?;
     Ok(PackageConfig {
         name,
         version,
         depends,
+        provides_plugs,
+        plugs,
+        exports,
+        env,
+        conflicts,
+        replaces,
     })
 }
 
@@ -251,6 +317,162 @@ pub fn parse_depends(doc: &KdlDocument) -> Result<Vec<Dependency>> {
     Ok(depends)
 }
 
+/// Checks that a plug name can be used as the name of its directory under
+/// `${dpt_dir}/plugs`.
+fn check_plug_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        bail!("`{}` is not a valid plug name!", name);
+    }
+    Ok(())
+}
+
+/// Parse the plugs a package provides from a package configuration kdl
+/// document
+pub fn parse_provided_plugs(doc: &KdlDocument) -> Result<Vec<ProvidedPlug>> {
+    let mut plugs: Vec<ProvidedPlug> = Vec::new();
+    for node in doc.nodes() {
+        if node.name().value() != "provides-plug" {
+            continue;
+        }
+        let name = node
+            .get(0)
+            .and_then(|x| x.as_string())
+            .ok_or(anyhow!("Name not specified for provided plug!"))?;
+        check_plug_name(name)?;
+        let mut paths = Vec::<PathBuf>::new();
+        for ent in node.entries().iter().skip(1) {
+            let path = ent
+                .value()
+                .as_string()
+                .ok_or(anyhow!("Path of plug `{}` is not a string!", name))?;
+            if !path.starts_with('/') {
+                bail!("Path `{}` of plug `{}` is not absolute!", path, name);
+            }
+            if Path::new(path)
+                .components()
+                .any(|x| x == Component::ParentDir)
+            {
+                bail!("Path `{}` of plug `{}` contains `..`!", path, name);
+            }
+            paths.push(PathBuf::from(path));
+        }
+        if paths.is_empty() {
+            bail!("No paths specified for plug `{}`!", name);
+        }
+        plugs.push(ProvidedPlug {
+            name: name.to_string(),
+            paths,
+        });
+    }
+    Ok(plugs)
+}
+
+/// Parse the names of the plugs a package plugs into from a package
+/// configuration kdl document
+pub fn parse_plugs(doc: &KdlDocument) -> Result<Vec<String>> {
+    let mut plugs: Vec<String> = Vec::new();
+    for node in doc.nodes() {
+        if node.name().value() == "plug" {
+            let name = node
+                .get(0)
+                .and_then(|x| x.as_string())
+                .ok_or(anyhow!("Name not specified for plug!"))?;
+            check_plug_name(name)?;
+            plugs.push(name.to_string());
+        }
+    }
+    Ok(plugs)
+}
+
+/// Parse the package names given to every node called field, like
+/// `conflicts foo bar`, from a package configuration kdl document
+pub fn parse_package_names(
+    doc: &KdlDocument,
+    field: &str,
+) -> Result<Vec<String>> {
+    let mut names: Vec<String> = Vec::new();
+    for node in doc.nodes().iter().filter(|x| x.name().value() == field) {
+        for ent in node.entries() {
+            let name = ent.value().as_string().ok_or(anyhow!(
+                "Package name in `{}` is not a string!",
+                field
+            ))?;
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// Parse the commands a package exports from a package configuration kdl
+/// document. The path of a command defaults to `/usr/bin/<name>`.
+pub fn parse_exports(doc: &KdlDocument) -> Result<Vec<Export>> {
+    let mut exports: Vec<Export> = Vec::new();
+    let Some(children) = doc.get("exports").and_then(|x| x.children()) else {
+        return Ok(exports);
+    };
+    for node in children.nodes() {
+        let name = node.name().value().to_string();
+        if name.contains('/') {
+            bail!("Exported command `{}` contains a `/`!", name);
+        }
+        let path = match node.get(0) {
+            Some(x) => x.as_string().ok_or(anyhow!(
+                "Path of exported command `{}` is not a string!",
+                name
+            ))?,
+            None => &format!("/usr/bin/{}", name),
+        };
+        if !path.starts_with('/') {
+            bail!("Path of exported command `{}` is not absolute!", name);
+        }
+        let mut args = Vec::<String>::new();
+        let mut env = Vec::<(String, String)>::new();
+        for option in node.children().map(|x| x.nodes()).unwrap_or_default() {
+            match option.name().value() {
+                "args" => {
+                    for ent in option.entries() {
+                        let arg = ent.value().as_string().ok_or(anyhow!(
+                            "Argument of exported command `{}` is not a string!",
+                            name
+                        ))?;
+                        args.push(arg.to_string());
+                    }
+                }
+                "env" => env.extend(parse_env(option)?),
+                x => bail!(
+                    "Unknown option `{}` for exported command `{}`!",
+                    x,
+                    name
+                ),
+            }
+        }
+        exports.push(Export {
+            name,
+            path: PathBuf::from(path),
+            args,
+            env,
+        });
+    }
+    Ok(exports)
+}
+
+/// Parses the environment variables of an `env` node, given as
+/// `VAR="value"`.
+pub fn parse_env(node: &KdlNode) -> Result<Vec<(String, String)>> {
+    let mut env = Vec::<(String, String)>::new();
+    for ent in node.entries() {
+        let var = ent.name().ok_or(anyhow!(
+            "Environment variables need to be given as VAR=\"value\"!"
+        ))?;
+        let value = ent.value().as_string().ok_or(anyhow!(
+            "Value of environment variable {} is not a string!",
+            var.value()
+        ))?;
+        env.push((var.value().to_string(), value.to_string()));
+    }
+    Ok(env)
+}
+
 /// Parses the name and version from a string.
 pub fn string_to_package(s: &str) -> Result<Package> {
     let version = s
@@ -310,6 +532,22 @@ version "145.54.12"
 
 depends "coreutils"
 depends python version="^8.9.112"
+
+provides-plug wayland "/plugs/wayland" "/usr/share/wayland-sessions"
+plug dbus
+
+env ABCD_THEME="dark" XDG_CURRENT_DESKTOP="abcd"
+
+conflicts efgh
+replaces abcd-legacy abcd-git
+
+exports {
+    abcd "/usr/lib/abcd/abcd" {
+        args "--verbose"
+        env ABCD_HOME="/var/lib/abcd"
+    }
+    abcd-cli
+}
 "###;
         let expected = PackageConfig {
             name: "abcd".to_string(),
@@ -324,11 +562,88 @@ depends python version="^8.9.112"
                     version_mask: "^8.9.112".to_string(),
                 },
             ],
+            provides_plugs: vec![ProvidedPlug {
+                name: "wayland".to_string(),
+                paths: vec![
+                    PathBuf::from("/plugs/wayland"),
+                    PathBuf::from("/usr/share/wayland-sessions"),
+                ],
+            }],
+            plugs: vec!["dbus".to_string()],
+            exports: vec![
+                Export {
+                    name: "abcd".to_string(),
+                    path: PathBuf::from("/usr/lib/abcd/abcd"),
+                    args: vec!["--verbose".to_string()],
+                    env: vec![(
+                        "ABCD_HOME".to_string(),
+                        "/var/lib/abcd".to_string(),
+                    )],
+                },
+                Export {
+                    name: "abcd-cli".to_string(),
+                    path: PathBuf::from("/usr/bin/abcd-cli"),
+                    args: vec![],
+                    env: vec![],
+                },
+            ],
+            env: vec![
+                ("ABCD_THEME".to_string(), "dark".to_string()),
+                ("XDG_CURRENT_DESKTOP".to_string(), "abcd".to_string()),
+            ],
+            conflicts: vec!["efgh".to_string()],
+            replaces: vec!["abcd-legacy".to_string(), "abcd-git".to_string()],
         };
         let x = get_package_config(s).unwrap();
         assert_eq!(x, expected);
     }
 
+    #[test]
+    fn plug_names_stay_in_plugs_dir() {
+        let config = |plugs: &str| {
+            get_package_config(&format!(
+                "name abcd\nversion \"1.0.0\"\n{}\n",
+                plugs
+            ))
+        };
+        assert!(config("provides-plug wayland \"/plugs/wayland\"").is_ok());
+        assert!(config("plug dbus").is_ok());
+        assert!(config("provides-plug \"a/b\" \"/plugs/a\"").is_err());
+        assert!(config("provides-plug \"..\" \"/plugs/a\"").is_err());
+        assert!(config("plug \"../etc\"").is_err());
+        assert!(config("plug \".\"").is_err());
+    }
+
+    #[test]
+    fn plug_paths_stay_in_root() {
+        let config = |path: &str| {
+            get_package_config(&format!(
+                "name abcd\nversion \"1.0.0\"\nprovides-plug x \"{}\"\n",
+                path
+            ))
+        };
+        assert!(config("/plugs/x").is_ok());
+        assert!(config("plugs/x").is_err());
+        assert!(config("/plugs/../../etc").is_err());
+        assert!(config("/..").is_err());
+    }
+
+    #[test]
+    fn versions_sort() {
+        let mut versions =
+            ["1.10.0", "git", "1.2", "1.9.1", "beta", "1.2.0", "0.9.0"];
+        versions.sort_by(|a, b| compare_versions(a, b));
+        assert_eq!(
+            versions,
+            ["0.9.0", "1.2", "1.2.0", "1.9.1", "1.10.0", "beta", "git"]
+        );
+        // Every order of the same versions sorts the same
+        let mut reversed = versions;
+        reversed.reverse();
+        reversed.sort_by(|a, b| compare_versions(a, b));
+        assert_eq!(reversed, versions);
+    }
+
     #[test]
     fn string_to_package_1() {
         assert_eq!(
@@ -0,0 +1,265 @@
+use std::{
+    collections::BTreeMap, fs::Permissions, os::unix::fs::PermissionsExt,
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, bail, Result};
+use kdl::{KdlDocument, KdlEntry, KdlNode};
+use log::warn;
+
+use crate::{
+    dpt_file::get_dpt_lock_location,
+    pkg::{parse_kdl, Package, PackageConfig},
+    run::make_path_relative,
+    sandbox::Mount,
+    store::{get_dpt_dir, get_package_dir, read_package_config},
+};
+
+/// Gets the directory holding the files that plugs share at run time, like
+/// sockets.
+pub fn get_plugs_location() -> PathBuf {
+    get_dpt_dir().join("plugs")
+}
+
+/// Finds the package providing each plug, bailing if more than one does.
+pub fn get_plug_providers(
+    configs: &[PackageConfig],
+) -> Result<BTreeMap<String, Package>> {
+    let mut providers = BTreeMap::<String, Package>::new();
+    for config in configs {
+        for plug in &config.provides_plugs {
+            let pkg = Package::new(config.name.clone(), config.version.clone());
+            if let Some(other) = providers.get(&plug.name) {
+                if other.name != pkg.name {
+                    bail!(
+                        "Plug `{}` is provided by both {} and {}!",
+                        plug.name,
+                        other.name,
+                        pkg.name
+                    );
+                }
+            }
+            providers.insert(plug.name.clone(), pkg);
+        }
+    }
+    for config in configs {
+        for plug in &config.plugs {
+            if !providers.contains_key(plug) {
+                warn!(
+                    "Nothing provides plug `{}` used by {}",
+                    plug, config.name
+                );
+            }
+        }
+    }
+    Ok(providers)
+}
+
+/// Creates the shared directories of the paths that plugs don't ship in
+/// their package, and removes those of plugs that nothing provides anymore.
+/// They are writable by everyone, like `/tmp`, so that the provider can
+/// create its sockets in them no matter which user runs it.
+pub fn create_plug_dirs(
+    configs: &[PackageConfig],
+    providers: &BTreeMap<String, Package>,
+) -> Result<()> {
+    let plugs = get_plugs_location();
+    if plugs.is_dir() {
+        for ent in std::fs::read_dir(&plugs)? {
+            let ent = ent?;
+            let name = ent.file_name().to_string_lossy().to_string();
+            if !providers.contains_key(&name) {
+                std::fs::remove_dir_all(ent.path())?;
+            }
+        }
+    }
+    for config in configs {
+        let dir = get_package_dir(&Package::new(
+            config.name.clone(),
+            config.version.clone(),
+        ));
+        for plug in &config.provides_plugs {
+            for path in &plug.paths {
+                let rel = make_path_relative(path);
+                if dir.join(&rel).symlink_metadata().is_ok() {
+                    continue;
+                }
+                let shared = plugs.join(&plug.name).join(&rel);
+                std::fs::DirBuilder::new().recursive(true).create(&shared)?;
+                std::fs::set_permissions(
+                    &shared,
+                    Permissions::from_mode(0o1777),
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Builds the `plugs` node of `dpt.lock`, recording the provider of each
+/// plug.
+pub fn plugs_node(providers: &BTreeMap<String, Package>) -> KdlNode {
+    let mut node = KdlNode::new("plugs");
+    let mut doc = KdlDocument::new();
+    for (plug, pkg) in providers {
+        let mut child = KdlNode::new(plug.as_str());
+        child.push(KdlEntry::new(pkg.name.clone()));
+        child.push(KdlEntry::new(pkg.version.clone()));
+        doc.nodes_mut().push(child);
+    }
+    node.set_children(doc);
+    node
+}
+
+/// Parses the providers of plugs from the `plugs` node of a lock file.
+pub fn parse_plug_providers(
+    doc: &KdlDocument,
+) -> Result<BTreeMap<String, Package>> {
+    let mut providers = BTreeMap::new();
+    let Some(children) = doc.get("plugs").and_then(|x| x.children()) else {
+        return Ok(providers);
+    };
+    for node in children.nodes() {
+        let arg = |i| {
+            node.get(i).and_then(|x| x.as_string()).ok_or(anyhow!(
+                "Malformed provider of plug `{}` in dpt.lock!",
+                node.name().value()
+            ))
+        };
+        providers.insert(
+            node.name().value().to_string(),
+            Package::new(arg(0)?.to_string(), arg(1)?.to_string()),
+        );
+    }
+    Ok(providers)
+}
+
+fn read_plug_providers() -> Result<BTreeMap<String, Package>> {
+    let lock = get_dpt_lock_location();
+    if !lock.exists() {
+        return Ok(BTreeMap::new());
+    }
+    parse_plug_providers(&parse_kdl(&std::fs::read_to_string(lock)?)?)
+}
+
+/// Gets the mounts that share plugs with the environment made of layers. The
+/// shared directories of plugs provided inside of the environment are bound
+/// writable, and the paths of plugs that packages in it plug into are bound
+/// from their provider. mounts are the ones already planned, which plug paths
+/// can't be inside of when they come from the host.
+pub fn plug_mounts(layers: &[PathBuf], mounts: &[Mount]) -> Result<Vec<Mount>> {
+    let configs = layers
+        .iter()
+        .filter(|x| x.join("dpt").join("pkg.kdl").is_file())
+        .map(|x| read_package_config(x))
+        .collect::<Result<Vec<PackageConfig>>>()?;
+    let providers = read_plug_providers()?;
+
+    let mut plugs = Vec::<(String, PathBuf)>::new();
+    for config in &configs {
+        for plug in &config.provides_plugs {
+            for path in &plug.paths {
+                plugs.push((plug.name.clone(), path.clone()));
+            }
+        }
+        for name in &config.plugs {
+            let Some(provider) = providers.get(name) else {
+                warn!("Nothing provides plug `{}`", name);
+                continue;
+            };
+            let provider = read_package_config(&get_package_dir(provider))?;
+            for plug in
+                provider.provides_plugs.iter().filter(|x| x.name == *name)
+            {
+                for path in &plug.paths {
+                    plugs.push((name.clone(), path.clone()));
+                }
+            }
+        }
+    }
+
+    let mut plug_mounts = Vec::<Mount>::new();
+    for (name, path) in plugs {
+        let target = make_path_relative(&path);
+        if plug_mounts.iter().any(|x| x.target() == target)
+            || layers
+                .iter()
+                .any(|x| x.join(&target).symlink_metadata().is_ok())
+        {
+            continue;
+        }
+        if let Some(bind) = mounts.iter().find(|x| {
+            matches!(x, Mount::Bind { .. }) && target.starts_with(x.target())
+        }) {
+            warn!(
+                "Path {} of plug `{}` is inside of /{}, which is bound from the host",
+                path.display(),
+                name,
+                bind.target().display()
+            );
+            continue;
+        }
+        let (src, read_only) = match providers.get(&name) {
+            Some(x) if get_package_dir(x).join(&target).exists() => {
+                (get_package_dir(x).join(&target), true)
+            }
+            _ => (get_plugs_location().join(&name).join(&target), false),
+        };
+        if !src.exists() {
+            warn!("{} of plug `{}` does not exist!", src.display(), name);
+            continue;
+        }
+        plug_mounts.push(Mount::Bind {
+            src,
+            target,
+            read_only,
+        });
+    }
+    Ok(plug_mounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pkg::ProvidedPlug;
+
+    fn config(name: &str, provides: &[&str], plugs: &[&str]) -> PackageConfig {
+        PackageConfig {
+            name: name.to_string(),
+            version: "1.0.0".to_string(),
+            depends: vec![],
+            provides_plugs: provides
+                .iter()
+                .map(|x| ProvidedPlug {
+                    name: x.to_string(),
+                    paths: vec![PathBuf::from("/plugs").join(x)],
+                })
+                .collect(),
+            plugs: plugs.iter().map(|x| x.to_string()).collect(),
+            exports: vec![],
+            env: vec![],
+            conflicts: vec![],
+            replaces: vec![],
+        }
+    }
+
+    #[test]
+    fn plug_providers() {
+        let configs = vec![
+            config("sway", &["wayland"], &["dbus"]),
+            config("dbus", &["dbus"], &[]),
+            config("foot", &[], &["wayland"]),
+        ];
+        let providers = get_plug_providers(&configs).unwrap();
+        assert_eq!(providers["wayland"].name, "sway");
+        assert_eq!(providers["dbus"].name, "dbus");
+
+        let mut doc = KdlDocument::new();
+        doc.nodes_mut().push(plugs_node(&providers));
+        assert_eq!(parse_plug_providers(&doc).unwrap(), providers);
+
+        let mut configs = configs;
+        configs.push(config("hyprland", &["wayland"], &[]));
+        get_plug_providers(&configs).unwrap_err();
+    }
+}
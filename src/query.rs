@@ -0,0 +1,564 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::{self, Display},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+use anyhow::{bail, Result};
+use indicatif::HumanBytes;
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::{
+    dpt_file::{get_dpt_lock_location, read_dpt_lock_file},
+    pkg::{compare_versions, string_to_package, Package, Version},
+    repo::{
+        dependency_edges, newest_package_from_name, package_to_onlinepackage,
+        DependencyEdge, OnlinePackage,
+    },
+    store::{get_installed_packages_without_dpt_file, get_package_dir},
+};
+
+/// Details on a package of the repositories, as shown by `dpt info`.
+#[derive(Debug, Serialize)]
+pub struct PackageInfo {
+    #[serde(flatten)]
+    pub package: OnlinePackage,
+    /// Every version the repositories have, oldest first.
+    pub versions: Vec<String>,
+    /// Whether this version is in the store.
+    pub installed: bool,
+}
+
+impl Display for PackageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let depends = self
+            .package
+            .depends
+            .iter()
+            .map(|x| match x.version_mask.is_empty() {
+                true => x.name.clone(),
+                false => format!("{} ({})", x.name, x.version_mask),
+            })
+            .collect::<Vec<String>>();
+        writeln!(f, "name: {}", self.package.name)?;
+        writeln!(f, "version: {}", self.package.version)?;
+        writeln!(f, "versions: {}", self.versions.join(", "))?;
+        writeln!(f, "url: {}", self.package.url)?;
+        writeln!(f, "depends: {}", depends.join(", "))?;
+        write!(
+            f,
+            "installed: {}",
+            if self.installed { "yes" } else { "no" }
+        )
+    }
+}
+
+/// A package in the store, as listed by `dpt list`. Its URL is its
+/// directory in the store.
+#[derive(Debug, Serialize)]
+pub struct StorePackage {
+    #[serde(flatten)]
+    pub package: OnlinePackage,
+    /// Bytes its files take up on disk.
+    pub size: u64,
+    /// Whether `dpt.lock` references it.
+    pub locked: bool,
+    /// The newest version in the repositories, if it's newer than this one.
+    /// Only looked up when asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upgrade: Option<String>,
+}
+
+impl Display for StorePackage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({})",
+            self.package.name,
+            self.package.version,
+            HumanBytes(self.size)
+        )?;
+        if let Some(x) = &self.upgrade {
+            write!(f, " -> {}", x)?;
+        }
+        if !self.locked {
+            write!(f, " [orphan]")?;
+        }
+        Ok(())
+    }
+}
+
+/// Gets the bytes the files in dir take up on disk.
+fn disk_usage(dir: &Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|x| x.metadata().ok())
+        .map(|x| x.blocks() * 512)
+        .sum()
+}
+
+/// Lists the packages in the store. If the packages of the repositories are
+/// given, the newer versions of the locked packages are looked up in them.
+pub fn list_store(
+    available: Option<&Vec<OnlinePackage>>,
+) -> Result<Vec<StorePackage>> {
+    let locked = match get_dpt_lock_location().exists() {
+        true => read_dpt_lock_file()?.packages,
+        false => Vec::new(),
+    };
+    let mut pkgs = get_installed_packages_without_dpt_file()?;
+    sort_packages(&mut pkgs);
+    let mut ret = Vec::<StorePackage>::new();
+    for package in pkgs {
+        let is_locked = locked.contains(&Package::new(
+            package.name.clone(),
+            package.version.clone(),
+        ));
+        let upgrade = match available {
+            Some(x) if is_locked => newest_package_from_name(&package.name, x)
+                .ok()
+                .filter(|x| {
+                    match (
+                        Version::from_str(&x.version),
+                        Version::from_str(&package.version),
+                    ) {
+                        (Ok(newest), Ok(current)) => newest > current,
+                        _ => false,
+                    }
+                })
+                .map(|x| x.version),
+            _ => None,
+        };
+        ret.push(StorePackage {
+            size: disk_usage(Path::new(&package.url)),
+            package,
+            locked: is_locked,
+            upgrade,
+        });
+    }
+    Ok(ret)
+}
+
+/// Sorts packages by name, and then by version from oldest to newest, as
+/// [`compare_versions`] orders them.
+fn sort_packages(pkgs: &mut [OnlinePackage]) {
+    pkgs.sort_by(|a, b| {
+        a.name
+            .cmp(&b.name)
+            .then_with(|| compare_versions(&a.version, &b.version))
+    });
+}
+
+/// Finds the packages whose name contains term, ignoring case, in their
+/// newest versions.
+pub fn search(term: &str, pkgs: &[OnlinePackage]) -> Vec<OnlinePackage> {
+    let term = term.to_lowercase();
+    let mut found = pkgs
+        .iter()
+        .filter(|x| x.name.to_lowercase().contains(&term))
+        .cloned()
+        .collect::<Vec<OnlinePackage>>();
+    sort_packages(&mut found);
+    // Only the last, which is the newest, of every name is kept
+    let mut newest = Vec::<OnlinePackage>::new();
+    for pkg in found {
+        if newest.last().is_some_and(|x| x.name == pkg.name) {
+            newest.pop();
+        }
+        newest.push(pkg);
+    }
+    newest
+}
+
+/// Gets the details on pkg out of the packages of the repositories.
+pub fn package_info(
+    pkg: &Package,
+    pkgs: &Vec<OnlinePackage>,
+) -> Result<PackageInfo> {
+    let package = package_to_onlinepackage(pkg, pkgs)?;
+    let mut versions = pkgs
+        .iter()
+        .filter(|x| x.name == pkg.name)
+        .cloned()
+        .collect::<Vec<OnlinePackage>>();
+    sort_packages(&mut versions);
+    let mut versions =
+        versions.into_iter().map(|x| x.version).collect::<Vec<_>>();
+    versions.dedup();
+    Ok(PackageInfo {
+        package,
+        versions,
+        installed: get_package_dir(pkg).exists(),
+    })
+}
+
+/// The dependency graph of packages, as printed by `dpt graph`.
+#[derive(Debug, Serialize)]
+pub struct DependencyGraph {
+    pub packages: Vec<Package>,
+    /// Refer to packages by their position in `packages`.
+    pub dependencies: Vec<DependencyEdge>,
+}
+
+impl DependencyGraph {
+    /// Builds the graph of the dependencies between pkgs.
+    pub fn new(mut pkgs: Vec<OnlinePackage>) -> Self {
+        sort_packages(&mut pkgs);
+        DependencyGraph {
+            dependencies: dependency_edges(&pkgs),
+            packages: pkgs.into_iter().map(|x| x.to_package()).collect(),
+        }
+    }
+
+    /// Writes the graph in the DOT language of Graphviz, labelling
+    /// dependencies with their version ranges.
+    pub fn to_dot(&self) -> String {
+        let quote = |s: String| {
+            format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+        };
+        let names = self
+            .packages
+            .iter()
+            .map(|x| quote(format!("{} {}", x.name, x.version)))
+            .collect::<Vec<String>>();
+        let mut dot = String::from("digraph dependencies {\n");
+        for name in &names {
+            dot.push_str(&format!("    {};\n", name));
+        }
+        for edge in &self.dependencies {
+            dot.push_str(&format!(
+                "    {} -> {}",
+                names[edge.from], names[edge.to]
+            ));
+            if !edge.version_mask.is_empty() {
+                dot.push_str(&format!(
+                    " [label={}]",
+                    quote(edge.version_mask.clone())
+                ));
+            }
+            dot.push_str(";\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// A package depending on the one asked about by `dpt rdepends`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ReverseDependency {
+    #[serde(flatten)]
+    pub package: Package,
+    /// Whether it depends on it itself, rather than through other packages.
+    pub direct: bool,
+}
+
+/// Finds the positions of the packages that arg names, which is either a
+/// name or `name-version`.
+pub fn find_packages(arg: &str, pkgs: &[OnlinePackage]) -> Result<Vec<usize>> {
+    let exact = string_to_package(arg).ok();
+    let found = pkgs
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| {
+            x.name == arg
+                || exact
+                    .as_ref()
+                    .is_some_and(|y| x.name == y.name && x.version == y.version)
+        })
+        .map(|(i, _)| i)
+        .collect::<Vec<usize>>();
+    if found.is_empty() {
+        bail!("Package `{}` not found!", arg);
+    }
+    Ok(found)
+}
+
+/// Finds the packages that depend on the ones arg names, directly or through
+/// others, with the direct ones first.
+pub fn reverse_dependencies(
+    arg: &str,
+    pkgs: &[OnlinePackage],
+) -> Result<Vec<ReverseDependency>> {
+    let mut pkgs = pkgs.to_vec();
+    sort_packages(&mut pkgs);
+    let edges = dependency_edges(&pkgs);
+    let mut current = find_packages(arg, &pkgs)?;
+    let mut seen = current.iter().copied().collect::<HashSet<usize>>();
+    let mut ret = Vec::<ReverseDependency>::new();
+    let mut direct = true;
+    while !current.is_empty() {
+        let mut next = Vec::<usize>::new();
+        for edge in &edges {
+            if current.contains(&edge.to) && seen.insert(edge.from) {
+                next.push(edge.from);
+            }
+        }
+        next.sort();
+        for i in &next {
+            ret.push(ReverseDependency {
+                package: pkgs[*i].clone().to_package(),
+                direct,
+            });
+        }
+        current = next;
+        direct = false;
+    }
+    Ok(ret)
+}
+
+/// Finds the shortest path of dependencies from the roots to each of the
+/// packages that arg names. Roots without a version start from their newest
+/// version, which is the one `dpt rebuild` picks.
+pub fn dependency_paths(
+    roots: &[Package],
+    arg: &str,
+    pkgs: &[OnlinePackage],
+) -> Result<Vec<Vec<Package>>> {
+    let edges = dependency_edges(pkgs);
+    let targets = find_packages(arg, pkgs)?;
+    let mut paths = Vec::<Vec<usize>>::new();
+    for root in roots {
+        let start = pkgs
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| {
+                x.name == root.name
+                    && (root.version.is_empty() || x.version == root.version)
+            })
+            .max_by(|(_, a), (_, b)| compare_versions(&a.version, &b.version));
+        if let Some((start, _)) = start {
+            paths.extend(shortest_paths(&edges, start, &targets));
+        }
+    }
+    Ok(paths
+        .into_iter()
+        .map(|x| x.iter().map(|i| pkgs[*i].clone().to_package()).collect())
+        .collect())
+}
+
+/// Gets the shortest path from start to each of the targets it leads to,
+/// going breadth first and remembering what each package was reached from.
+fn shortest_paths(
+    edges: &[DependencyEdge],
+    start: usize,
+    targets: &[usize],
+) -> Vec<Vec<usize>> {
+    let mut reached_from = HashMap::<usize, usize>::new();
+    let mut queue = VecDeque::from([start]);
+    while let Some(current) = queue.pop_front() {
+        for edge in edges.iter().filter(|x| x.from == current) {
+            if edge.to != start && !reached_from.contains_key(&edge.to) {
+                reached_from.insert(edge.to, current);
+                queue.push_back(edge.to);
+            }
+        }
+    }
+    let mut paths = Vec::<Vec<usize>>::new();
+    for target in targets {
+        if *target != start && !reached_from.contains_key(target) {
+            continue;
+        }
+        let mut path = vec![*target];
+        while let Some(x) = reached_from.get(&path[path.len() - 1]) {
+            path.push(*x);
+        }
+        path.reverse();
+        paths.push(path);
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pkg::Dependency;
+
+    fn online(name: &str, version: &str) -> OnlinePackage {
+        OnlinePackage {
+            name: name.to_string(),
+            version: version.to_string(),
+            url: format!("https://my.repo.here/{}-{}.dpt", name, version),
+            depends: vec![],
+        }
+    }
+
+    fn depending(
+        name: &str,
+        version: &str,
+        depends: &[(&str, &str)],
+    ) -> OnlinePackage {
+        OnlinePackage {
+            depends: depends
+                .iter()
+                .map(|(name, version_mask)| Dependency {
+                    name: name.to_string(),
+                    version_mask: version_mask.to_string(),
+                })
+                .collect(),
+            ..online(name, version)
+        }
+    }
+
+    fn package(name: &str, version: &str) -> Package {
+        Package::new(name.to_string(), version.to_string())
+    }
+
+    #[test]
+    fn reverse_dependencies_and_paths() {
+        let pkgs = vec![
+            depending("app", "1.0.0", &[("gtk", ""), ("libc", "")]),
+            depending("gtk", "3.0.0", &[("glib", ">=2.1.0")]),
+            depending("glib", "2.0.0", &[("libc", "")]),
+            depending("glib", "2.2.0", &[("libc", "")]),
+            depending("tool", "1.0.0", &[("glib", "2.0.0")]),
+            depending("viewer", "1.0.0", &[("glib", "")]),
+            online("libc", "1.0.0"),
+        ];
+        assert_eq!(
+            reverse_dependencies("glib-2.2.0", &pkgs).unwrap(),
+            [
+                ReverseDependency {
+                    package: package("gtk", "3.0.0"),
+                    direct: true,
+                },
+                ReverseDependency {
+                    package: package("viewer", "1.0.0"),
+                    direct: true,
+                },
+                ReverseDependency {
+                    package: package("app", "1.0.0"),
+                    direct: false,
+                },
+            ]
+        );
+        assert_eq!(reverse_dependencies("glib", &pkgs).unwrap().len(), 4);
+        // Only the version the resolver picks is depended on
+        assert_eq!(
+            reverse_dependencies("glib-2.0.0", &pkgs).unwrap(),
+            [ReverseDependency {
+                package: package("tool", "1.0.0"),
+                direct: true,
+            }]
+        );
+        assert!(reverse_dependencies("nothing", &pkgs).is_err());
+
+        let roots = [package("app", ""), package("tool", "1.0.0")];
+        assert_eq!(
+            dependency_paths(&roots, "libc", &pkgs).unwrap(),
+            [
+                vec![package("app", "1.0.0"), package("libc", "1.0.0")],
+                vec![
+                    package("tool", "1.0.0"),
+                    package("glib", "2.0.0"),
+                    package("libc", "1.0.0"),
+                ],
+            ]
+        );
+        assert_eq!(
+            dependency_paths(&roots, "glib", &pkgs).unwrap(),
+            [
+                vec![
+                    package("app", "1.0.0"),
+                    package("gtk", "3.0.0"),
+                    package("glib", "2.2.0"),
+                ],
+                vec![package("tool", "1.0.0"), package("glib", "2.0.0")],
+            ]
+        );
+        assert_eq!(
+            dependency_paths(&[package("viewer", "")], "glib", &pkgs).unwrap(),
+            [vec![package("viewer", "1.0.0"), package("glib", "2.2.0")]]
+        );
+    }
+
+    #[test]
+    fn many_paths() {
+        // Every layer doubles the number of paths through the graph, of
+        // which only the shortest is kept
+        let mut pkgs = vec![online("end", "1.0.0")];
+        let mut below = "end".to_string();
+        for i in 0..40 {
+            let (a, b) = (format!("a{}", i), format!("b{}", i));
+            pkgs.push(depending(&a, "1.0.0", &[(&below, "")]));
+            pkgs.push(depending(&b, "1.0.0", &[(&below, "")]));
+            below = format!("top{}", i);
+            pkgs.push(depending(&below, "1.0.0", &[(&a, ""), (&b, "")]));
+        }
+        let paths =
+            dependency_paths(&[package(&below, "")], "end", &pkgs).unwrap();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].len(), 81);
+    }
+
+    #[test]
+    fn invalid_versions_are_skipped() {
+        let pkgs = vec![
+            depending("app", "1.0.0", &[("libc", "")]),
+            // Can't be resolved, as the only version of bad is invalid
+            depending("other", "1.0.0", &[("libc", ""), ("bad", "")]),
+            depending("tool", "1.0.0", &[("libc", ">=one.0")]),
+            depending("bad", "one", &[("libc", "")]),
+            online("libc", "1.0.0"),
+        ];
+        assert_eq!(
+            reverse_dependencies("libc", &pkgs).unwrap(),
+            [ReverseDependency {
+                package: package("app", "1.0.0"),
+                direct: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn graph_dot() {
+        let graph = DependencyGraph::new(vec![
+            depending("app", "1.0.0", &[("lib\"c", ">=1.0.0"), ("gtk", "")]),
+            online("lib\"c", "1.0.0"),
+            online("gtk", "3.0.0"),
+        ]);
+        assert_eq!(
+            graph.to_dot(),
+            r#"digraph dependencies {
+    "app 1.0.0";
+    "gtk 3.0.0";
+    "lib\"c 1.0.0";
+    "app 1.0.0" -> "lib\"c 1.0.0" [label=">=1.0.0"];
+    "app 1.0.0" -> "gtk 3.0.0";
+}
+"#
+        );
+    }
+
+    #[test]
+    fn search_newest() {
+        let pkgs = vec![
+            online("libfoo", "1.10.0"),
+            online("foo", "1.0.0"),
+            online("libfoo", "1.9.0"),
+            online("bar", "2.0.0"),
+        ];
+        assert_eq!(
+            search("FOO", &pkgs),
+            [online("foo", "1.0.0"), online("libfoo", "1.10.0")]
+        );
+        let info = serde_json::to_value(
+            package_info(&Package::new("bar".into(), "2.0.0".into()), &pkgs)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            info,
+            serde_json::json!({
+                "name": "bar",
+                "version": "2.0.0",
+                "url": "https://my.repo.here/bar-2.0.0.dpt",
+                "depends": [],
+                "versions": ["2.0.0"],
+                "installed": false,
+            })
+        );
+    }
+}
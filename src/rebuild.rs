@@ -0,0 +1,160 @@
+use anyhow::{anyhow, Context, Result};
+use kdl::{KdlDocument, KdlEntry, KdlNode, KdlValue};
+use log::{error, warn};
+use std::{collections::HashSet, fs::write};
+
+use crate::{
+    base::rebuild_base,
+    conflicts::{find_conflicts, Conflict},
+    dpt_file::{read_dpt_file, read_dpt_file_document},
+    env::get_environment_layers,
+    exports::export_packages,
+    pkg::Package,
+    plug::{create_plug_dirs, get_plug_providers, plugs_node},
+    repo::{
+        get_all_available_packages, install_pkg_and_dependencies,
+        newest_package_from_name, package_to_onlinepackage,
+        plan_pkg_and_dependencies, InstallResult, OnlinePackage,
+    },
+    store::{
+        get_dpt_dir, get_installed_packages, get_package_dir, lock_package_dir,
+        read_package_config, store_is_immutable,
+    },
+};
+
+/// Rebuilds the system according to the dpt file, writing `dpt.lock`.
+/// Returns the packages of the system along with whether they were installed.
+/// With dry_run nothing is changed, and the results are what rebuilding would
+/// do.
+pub fn rebuild(dry_run: bool) -> Result<Vec<(OnlinePackage, InstallResult)>> {
+    let dpt = read_dpt_file()?;
+    let was_immutable = store_is_immutable()?;
+    let mut done_list: Vec<(OnlinePackage, InstallResult)> = Vec::new();
+    let repo_packages = get_all_available_packages()?;
+    let mut exported = Vec::new();
+
+    for package in dpt.packages.iter() {
+        let online_package = if package.version.is_empty() {
+            newest_package_from_name(&package.name, &repo_packages)
+        } else {
+            package_to_onlinepackage(package, &repo_packages)
+        }
+        .context(anyhow!("Package {} is not found in repository!", package))?;
+        if dry_run {
+            plan_pkg_and_dependencies(
+                &online_package,
+                &repo_packages,
+                &mut done_list,
+            )?;
+        } else {
+            install_pkg_and_dependencies(
+                &online_package,
+                &repo_packages,
+                &mut done_list,
+                false,
+            )?;
+        }
+        exported.push(online_package);
+    }
+    let done_list = remove_duplicates(done_list);
+    if dry_run {
+        return Ok(done_list);
+    }
+
+    rebuild_base(&dpt).context("Failed to build base!")?;
+
+    let mut dpt_lock = KdlDocument::new();
+
+    let mut packages_node = KdlNode::new("packages");
+    let mut packages_doc = KdlDocument::new();
+
+    let configs = done_list
+        .iter()
+        .map(|x| {
+            read_package_config(&get_package_dir(&x.0.clone().to_package()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let providers = get_plug_providers(&configs)?;
+    create_plug_dirs(&configs, &providers)
+        .context("Failed to create the directories of plugs")?;
+
+    for x in &done_list {
+        let mut node = KdlNode::new(x.0.name.clone());
+        node.entries_mut()
+            .push(KdlEntry::new(KdlValue::String(x.0.version.clone())));
+        packages_doc.nodes_mut().push(node);
+    }
+
+    packages_node.set_children(packages_doc);
+    dpt_lock.nodes_mut().push(packages_node);
+    if !providers.is_empty() {
+        dpt_lock.nodes_mut().push(plugs_node(&providers));
+    }
+
+    // Sandbox overrides take effect on rebuild, like the package list
+    let dpt_file = read_dpt_file_document()?;
+    for field in ["sandbox", "strict-conflicts", "immutable-store"] {
+        if let Some(node) = dpt_file.get(field) {
+            dpt_lock.nodes_mut().push(node.clone());
+        }
+    }
+
+    write(get_dpt_dir().join("dpt.lock"), dpt_lock.to_string())
+        .context("Failed to write dpt.lock file")?;
+
+    // The packages installed above were locked as of the last rebuild
+    if dpt.immutable_store != was_immutable {
+        for (pkg, _) in done_list
+            .iter()
+            .filter(|x| matches!(x.1, InstallResult::Installed))
+        {
+            lock_package_dir(
+                &get_package_dir(&pkg.clone().to_package()),
+                dpt.immutable_store,
+            )?;
+        }
+    }
+
+    // Only the packages in the dpt file are exported, so that the commands
+    // of their dependencies don't shadow the host's.
+    let exported = exported
+        .into_iter()
+        .map(|x| x.to_package())
+        .collect::<Vec<_>>();
+    export_packages(&exported).context("Failed to export packages")?;
+    report_conflicts(&exported)?;
+    Ok(done_list)
+}
+
+/// Reports the files that packages conflict on in the environments of pkgs,
+/// and the packages that can't be run because of conflicting packages.
+fn report_conflicts(pkgs: &[Package]) -> Result<()> {
+    let installed = get_installed_packages()?;
+    let mut reported = HashSet::<Conflict>::new();
+    for pkg in pkgs {
+        let conflicts = get_environment_layers(&vec![pkg.clone()], &installed)
+            .and_then(|layers| find_conflicts(&layers));
+        match conflicts {
+            Ok(conflicts) => {
+                for conflict in conflicts {
+                    if !reported.contains(&conflict) {
+                        warn!("{}", conflict);
+                        reported.insert(conflict);
+                    }
+                }
+            }
+            Err(e) => error!("{} can't be run: {:#}", pkg.name, e),
+        }
+    }
+    Ok(())
+}
+
+/// Removes the packages that are in l more than once, keeping the first
+/// result of installing them.
+fn remove_duplicates(
+    mut l: Vec<(OnlinePackage, InstallResult)>,
+) -> Vec<(OnlinePackage, InstallResult)> {
+    let mut seen = HashSet::new();
+    l.retain(|c| seen.insert(c.0.clone()));
+    l
+}
@@ -4,22 +4,28 @@ use anyhow::Context;
 use anyhow::{bail, Result};
 use indicatif::{ProgressBar, ProgressStyle};
 use kdl::{KdlDocument, KdlError, KdlNode};
+use log::warn;
 use pubgrub::OfflineDependencyProvider;
 use pubgrub::PubGrubError;
 use pubgrub::Ranges;
 use pubgrub::{DefaultStringReporter, Reporter};
 use reqwest::blocking::Client;
+use serde::Serialize;
 use std::fmt::{self, Display};
 use std::fs::DirBuilder;
 use std::io::Read;
 use std::path::PathBuf;
 
 use crate::pkg::{self, Dependency, Package};
-use crate::store::get_store_location;
+use crate::store::{
+    get_package_dir, get_store_location, lock_package_dir, store_is_immutable,
+    unlock_package_dir,
+};
+use crate::verify::record_manifest;
 
 type VersionSet = Ranges<Version>;
 
-#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+#[derive(Debug, PartialEq, Clone, Hash, Eq, Serialize)]
 pub struct OnlinePackage {
     pub name: String,
     pub version: String,
@@ -47,12 +53,22 @@ impl OnlinePackage {
     }
 }
 
-#[derive(Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum InstallResult {
     Installed,
+    /// The package was already in the store.
     Ignored,
 }
 
+/// A package and what installing it did, in the form printed by `--json`.
+#[derive(Serialize)]
+pub struct InstallSummary<'a> {
+    #[serde(flatten)]
+    pub package: &'a OnlinePackage,
+    pub result: &'a InstallResult,
+}
+
 /// Returns a list of repository's URLs
 pub fn get_repositories() -> Result<Vec<String>> {
     let repo_file = get_config_option("repos")
@@ -71,7 +87,7 @@ pub fn get_repositories() -> Result<Vec<String>> {
 pub fn fetch_file(url: &str) -> Result<Vec<u8>> {
     let client = Client::new();
 
-    let response = client.get(url).send()?;
+    let response = client.get(url).send()?.error_for_status()?;
 
     let total_size = match response.content_length() {
         Some(x) => x,
@@ -170,8 +186,13 @@ pub fn parse_repository_index(
 
         let name = get_kdl_string_prop("name", pkg)?;
         let version = get_kdl_string_prop("version", pkg)?;
-        let url =
-            push_onto_url(base_url, get_kdl_string_prop("path", pkg)?.as_str());
+        let path = get_kdl_string_prop("path", pkg)?;
+        // Indexes generated with a base URL contain absolute URLs
+        let url = if path.contains("://") {
+            path
+        } else {
+            push_onto_url(base_url, &path)
+        };
 
         let children = pkg.children();
 
@@ -190,16 +211,44 @@ pub fn parse_repository_index(
     Ok(ret)
 }
 
+/// Gets the paths of the shards listed in the manifest of a split index
+pub fn parse_index_shards(index: &str) -> Result<Vec<String>> {
+    let doc = pkg::parse_kdl(index)?;
+    let mut ret: Vec<String> = Vec::new();
+    for node in doc.nodes() {
+        if node.name().value() == "shard" {
+            ret.push(get_kdl_string_prop("path", node)?);
+        }
+    }
+    Ok(ret)
+}
+
+/// Fetches an index file, preferring its zstd compressed variant if the
+/// repository has one
+pub fn fetch_index(url: &str) -> Result<String> {
+    let index = match fetch_file(&(url.to_owned() + ".zst")) {
+        Ok(x) => zstd::decode_all(&x[..])
+            .context(format!("Failed to decompress {}.zst", url))?,
+        Err(_) => fetch_file(url)?,
+    };
+    Ok(String::from_utf8(index)?)
+}
+
 /// Get all packages that are available on all repositories
 pub fn get_all_available_packages() -> Result<Vec<OnlinePackage>> {
     let repos = get_repositories()?;
 
     let mut ret: Vec<OnlinePackage> = Vec::new();
     for repo in repos {
-        let index = fetch_file(&push_onto_url(repo.as_str(), "index.kdl"))?;
-        let index = std::str::from_utf8(&index)?;
-        let mut packages = parse_repository_index(index, &repo)?;
+        let index = fetch_index(&push_onto_url(repo.as_str(), "index.kdl"))?;
+        let mut packages = parse_repository_index(&index, &repo)?;
         ret.append(&mut packages);
+
+        for shard in parse_index_shards(&index)? {
+            let shard = fetch_index(&push_onto_url(repo.as_str(), &shard))?;
+            let mut packages = parse_repository_index(&shard, &repo)?;
+            ret.append(&mut packages);
+        }
     }
 
     Ok(ret)
@@ -245,6 +294,95 @@ pub fn get_dependency_provider_for_packages(
     Ok(ret)
 }
 
+/// A dependency of a package on another, by their positions in a list of
+/// packages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DependencyEdge {
+    pub from: usize,
+    pub to: usize,
+    /// The version range the dependency was declared with.
+    #[serde(rename = "version")]
+    pub version_mask: String,
+}
+
+/// Finds the dependencies between packages as they are resolved, linking
+/// each dependency of a package to the version picked for it when resolving
+/// the dependencies of that package, like its environment does. Packages with
+/// versions that can't be parsed, dependencies with ranges that can't be, and
+/// packages whose dependencies can't be resolved are left out, with a
+/// warning.
+pub fn dependency_edges(packages: &[OnlinePackage]) -> Vec<DependencyEdge> {
+    let versions = packages
+        .iter()
+        .map(|x| {
+            Version::from_str(&x.version)
+                .inspect_err(|e| {
+                    warn!(
+                        "Skipping {} {} in the dependency graph: {}",
+                        x.name, x.version, e
+                    )
+                })
+                .ok()
+        })
+        .collect::<Vec<Option<Version>>>();
+
+    let mut provider = OfflineDependencyProvider::<String, VersionSet>::new();
+    for (pkg, version) in packages.iter().zip(&versions) {
+        let Some(version) = version else {
+            continue;
+        };
+        let mut depends = Vec::<(String, VersionSet)>::new();
+        for dep in &pkg.depends {
+            match parse_version_range(&dep.version_mask) {
+                Ok(x) => depends.push((dep.name.clone(), x)),
+                Err(e) => warn!(
+                    "Skipping the invalid version range of {} in {}: {}",
+                    dep.name, pkg.name, e
+                ),
+            }
+        }
+        provider.add_dependencies(pkg.name.clone(), version.clone(), depends);
+    }
+
+    let mut ret = Vec::<DependencyEdge>::new();
+    for (from, pkg) in packages.iter().enumerate() {
+        let Some(version) = &versions[from] else {
+            continue;
+        };
+        let resolved = match pubgrub::resolve(
+            &provider,
+            pkg.name.clone(),
+            version.clone(),
+        ) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(
+                    "Skipping {} {} in the dependency graph, as its \
+                        dependencies can't be resolved: {}",
+                    pkg.name, pkg.version, e
+                );
+                continue;
+            }
+        };
+        for dep in &pkg.depends {
+            let Some(picked) = resolved.get(&dep.name) else {
+                continue;
+            };
+            let to = packages.iter().zip(&versions).position(|(x, v)| {
+                x.name == dep.name && v.as_ref() == Some(picked)
+            });
+            if let Some(to) = to {
+                ret.push(DependencyEdge {
+                    from,
+                    to,
+                    version_mask: dep.version_mask.clone(),
+                });
+            }
+        }
+    }
+    ret
+}
+
 /// Converts by looping through the package list to find a match. Short circuted
 pub fn package_to_onlinepackage(
     package: &Package,
@@ -342,6 +480,7 @@ pub fn install_pkg(
 
     if out_path.exists() {
         if reinstall {
+            unlock_package_dir(&out_path)?;
             std::fs::remove_dir_all(&out_path)?;
         } else {
             return Ok(InstallResult::Ignored);
@@ -353,6 +492,10 @@ pub fn install_pkg(
     let mut archive = pkg::decompress_pkg_read(&file[..])?;
 
     archive.unpack(&out_path)?;
+    // Packages run as root could otherwise change the ones of the other
+    // environments, through the hardlinks to the store
+    lock_package_dir(&out_path, store_is_immutable()?)?;
+    record_manifest(&pkg.clone().to_package())?;
 
     Ok(InstallResult::Installed)
 }
@@ -384,6 +527,29 @@ pub fn install_pkg_and_dependencies(
     Ok(())
 }
 
+/// Finds what `install_pkg_and_dependencies` would do for a package and its
+/// dependencies, without changing anything
+pub fn plan_pkg_and_dependencies(
+    pkg: &OnlinePackage,
+    pkgs: &Vec<OnlinePackage>,
+    done_list: &mut Vec<(OnlinePackage, InstallResult)>,
+) -> Result<()> {
+    let dependencies =
+        resolve_dependencies_for_package(pkgs, &pkg.clone().to_package())?;
+    for depends in std::iter::once(pkg.clone()).chain(dependencies) {
+        if done_list.iter().any(|x| x.0 == depends) {
+            continue;
+        }
+        let result =
+            match get_package_dir(&depends.clone().to_package()).exists() {
+                true => InstallResult::Ignored,
+                false => InstallResult::Installed,
+            };
+        done_list.push((depends, result));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -427,6 +593,31 @@ package name=example version="1.2.3" path="my-pkg.dpt" {
         assert_eq!(x, expected);
     }
 
+    #[test]
+    fn parse_repository_index_absolute() {
+        let index = r###"
+package name=test version="1.0.0" path="https://mirror.example/test.dpt"
+            "###;
+        let x =
+            parse_repository_index(index, "https://my.repo.here/dpt").unwrap();
+        assert_eq!(x[0].url, "https://mirror.example/test.dpt");
+    }
+
+    #[test]
+    fn parse_index_shards_1() {
+        let index = r###"
+shard path="index/a.kdl"
+shard path="index/b.kdl"
+            "###;
+        assert_eq!(
+            parse_index_shards(index).unwrap(),
+            vec!["index/a.kdl".to_string(), "index/b.kdl".to_string()]
+        );
+        assert!(parse_repository_index(index, "https://my.repo.here/dpt")
+            .unwrap()
+            .is_empty());
+    }
+
     #[test]
     fn resolve_1() {
         let packages = vec![
@@ -1,40 +1,104 @@
-use log::error;
-use nix::mount::MsFlags;
+use log::{debug, error};
+use nix::{
+    errno::Errno,
+    libc,
+    mount::{umount2, MntFlags, MsFlags},
+    sched::{unshare, CloneFlags},
+    sys::stat::Mode,
+    sys::{
+        signal::{kill, killpg, SigSet, SigmaskHow, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{
+        chdir, fork, getpgrp, getpid, isatty, mkdir, pivot_root, setpgid,
+        tcgetpgrp, tcsetpgrp, ForkResult, Pid,
+    },
+};
 use std::{
+    collections::BTreeMap,
+    ffi::{CStr, CString},
+    os::{
+        fd::BorrowedFd,
+        unix::{ffi::OsStrExt, process::CommandExt},
+    },
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
 };
 
-use anyhow::{bail, Context, Result};
-use rand::prelude::*;
-use sys_mount::{unmount, UnmountFlags};
+use anyhow::{anyhow, bail, Context, Result};
+use uzers::{get_current_gid, get_effective_uid, switch::set_current_uid};
 
 use crate::{
-    pkg::Package,
+    cache::get_cached_environment,
+    conflicts::conflicts_are_strict,
+    env::{get_environment_layers, layers_can_be_stacked, LinkMode},
+    exec::{compile_seccomp_filter, get_groups, harden},
+    pkg::{Export, Package, PackageConfig},
+    plug::plug_mounts,
+    repo::package_to_onlinepackage,
+    sandbox::{
+        get_sandbox, isolate_network, mount_steps, plan_mounts,
+        remount_read_only, run_steps, target_steps, Sandbox,
+    },
     store::{
         get_dpt_dir, get_installed_packages,
-        get_installed_packages_without_dpt_file,
+        get_installed_packages_without_dpt_file, read_package_config,
+        store_is_immutable,
     },
+    userns::{self, IdMaps},
 };
 
-pub fn get_run_location() -> PathBuf {
-    match crate::config::get_config_option(&"run".to_string()) {
-        Some(x) => PathBuf::from(x),
-        None => PathBuf::from("/dpt/run/"),
+/// How `run` enters an environment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// A mount namespace created as root, which needs a setuid binary.
+    Setuid,
+    /// A user and mount namespace owned by the user running the package.
+    Rootless { gid: u32 },
+}
+
+impl Backend {
+    /// Gets the id maps of the user namespace entered as uid, if any.
+    fn id_maps(&self, uid: u32) -> Option<IdMaps> {
+        match self {
+            Backend::Rootless { gid } => Some(IdMaps::new(uid, *gid)),
+            Backend::Setuid => None,
+        }
     }
 }
 
-pub fn get_random_string(length: usize) -> String {
-    let mut rng = rand::rng();
-    let mut ret = String::new();
-    for _ in 0..length {
-        ret.push(rng.sample(rand::distr::Alphanumeric) as char)
+/// Options for how a package is run, given on the command line.
+#[derive(Clone, Debug, Default)]
+pub struct RunOptions {
+    /// Environment variables set over all of the others.
+    pub env: Vec<(String, String)>,
+    /// Whether to start from an almost empty environment instead of the
+    /// caller's.
+    pub clear_env: bool,
+    /// The working directory inside of the environment. Defaults to the
+    /// current one, or `/` if it isn't visible there.
+    pub cwd: Option<PathBuf>,
+}
+
+/// Picks the backend to run a package as `uid`, preferring user namespaces
+/// and falling back to the setuid path only when they are unavailable.
+pub fn select_backend(uid: u32) -> Result<Backend> {
+    let gid = get_current_gid();
+    if uid != 0 && userns::user_namespaces_available(uid, gid) {
+        userns::drop_privileges(uid, gid)?;
+        return Ok(Backend::Rootless { gid });
+    }
+    if get_effective_uid() != 0 {
+        bail!("User namespaces are unavailable, so dpt needs to be installed setuid or run as root!");
     }
-    ret
+    set_current_uid(0)?;
+    Ok(Backend::Setuid)
 }
 
-pub fn join_proper(a: &Path, b: &Path) -> Result<PathBuf> {
-    Ok(a.join(make_path_relative(b)))
+pub fn get_run_location() -> PathBuf {
+    match crate::config::get_config_option(&"run".to_string()) {
+        Some(x) => PathBuf::from(x),
+        None => PathBuf::from("/dpt/run/"),
+    }
 }
 
 pub fn make_path_relative(a: &Path) -> PathBuf {
@@ -44,47 +108,40 @@ pub fn make_path_relative(a: &Path) -> PathBuf {
     }
 }
 
+/// Converts a path for the system calls made between fork and exec, which
+/// can't allocate.
+pub fn path_to_cstring(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .context(anyhow!("{} contains a NUL byte!", path.display()))
+}
+
 /// Makes src's contents show up at target
-pub fn bind_mount_(src: &Path, target: &Path) -> Result<(), std::io::Error> {
+pub fn bind_mount_(src: &CStr, target: &CStr) -> Result<(), std::io::Error> {
     let mut flags = MsFlags::MS_BIND;
     flags.insert(MsFlags::MS_REC);
     nix::mount::mount(
-        Option::<&Path>::Some(src),
+        Some(src),
         target,
-        Option::<&Path>::None,
+        Option::<&CStr>::None,
         flags,
-        Option::<&Path>::None,
+        Option::<&CStr>::None,
     )?;
     nix::mount::mount(
-        Option::<&Path>::None,
+        Option::<&CStr>::None,
         target,
-        Option::<&Path>::None,
+        Option::<&CStr>::None,
         MsFlags::MS_SLAVE.union(MsFlags::MS_REC),
-        Option::<&Path>::None,
+        Option::<&CStr>::None,
     )?;
     Ok(())
 }
 
-pub fn bind_mount(src: &Path, target: &Path) -> Result<()> {
-    if src.is_dir() {
-        std::fs::DirBuilder::new().recursive(true).create(&target)?;
-    } else {
-        std::fs::DirBuilder::new()
-            .recursive(true)
-            .create(&target.parent().unwrap_or(Path::new("/")))?;
-        std::fs::File::create(&target)?;
-    }
-    match bind_mount_(&src, &target) {
-        Err(x) => bail!(x.to_string()),
-        Ok(_) => Ok(()),
-    }
-}
-
 pub fn run_pkg(
     pkg: &Package,
     uid: u32,
     args: Vec<String>,
     cmd: Option<&str>,
+    options: &RunOptions,
     allow_non_dpt_file: bool,
 ) -> Result<i32> {
     run_multiple_packages(
@@ -92,118 +149,504 @@ pub fn run_pkg(
         uid,
         args,
         cmd,
+        options,
         allow_non_dpt_file,
     )
 }
 
+/// A process to run in an environment, and how to run it.
+pub struct Invocation<'a> {
+    pub proc: std::process::Command,
+    /// The user it runs as.
+    pub uid: u32,
+    pub backend: Backend,
+    /// Limits what it sees of the host.
+    pub sandbox: &'a Sandbox,
+    /// Its working directory inside of the environment, which has to exist.
+    /// Defaults to the current one, or `/` if it isn't visible there.
+    pub cwd: Option<&'a Path>,
+}
+
+/// Runs the process of invocation in the environment made of layers. When
+/// stacked, the layers are mounted with overlayfs under out_dir, otherwise
+/// they have to be linked into `out_dir/root` already.
 pub fn run_pkg_(
     out_dir: &Path,
-    uid: u32,
-    args: Vec<String>,
-    cmd: &str,
+    layers: &[PathBuf],
+    stacked: bool,
+    invocation: Invocation,
 ) -> Result<i32> {
+    let Invocation {
+        mut proc,
+        uid,
+        backend,
+        sandbox,
+        cwd,
+    } = invocation;
     std::fs::DirBuilder::new()
         .recursive(true)
         .create(&out_dir)?;
 
     let fpkg_dir = get_dpt_dir();
 
-    // Bind mount dpt dir inside the out_dir
-    let fpkg_target = join_proper(&out_dir, &fpkg_dir)?;
-    bind_mount(&fpkg_dir, &fpkg_target)?;
+    let root = out_dir.join("root");
+    let stack = stacked
+        .then(|| Stack::new(layers, out_dir, &fpkg_dir))
+        .transpose()?;
 
-    let mut binds = Vec::<PathBuf>::new();
+    let mut mounts =
+        plan_mounts(sandbox, layers, &fpkg_dir, &get_run_location());
+    mounts.extend(plug_mounts(layers, &mounts)?);
+    let network = sandbox.network();
 
-    for bind in vec![
-        "dev", "mnt", "media", "run", "var", "home", "tmp", "proc", "tmp",
-    ] {
-        let dir = Path::new("/").join(bind);
-        let dir_target = out_dir.join(bind);
-        if dir_target.exists() {
-            continue;
+    let (cwd, cwd_required) = match cwd {
+        Some(x) => (x.to_path_buf(), true),
+        None => (std::env::current_dir().unwrap_or(PathBuf::from("/")), false),
+    };
+    let gid = get_current_gid();
+    let groups = get_groups(&fpkg_dir.join("base"), uid, gid);
+    let filter = compile_seccomp_filter(sandbox.seccomp())?;
+    let id_maps = backend.id_maps(uid);
+
+    // Missing mount targets are made in the upper directory, as the lower
+    // ones can't be written to from a user namespace
+    let targets = target_steps(
+        &mounts,
+        &match stacked {
+            true => out_dir.join("upper"),
+            false => root.clone(),
+        },
+    )?;
+    let mounts = mount_steps(&mounts, &root)?;
+    let root = path_to_cstring(&root)?;
+    let cwd = path_to_cstring(&cwd)?;
+
+    // The closure runs in the forked child right before it execs, where only
+    // system calls are safe, so everything it needs is built beforehand. All
+    // of the mounts are made in a mount namespace of its own, so they go away
+    // with it and never show up under the run directory on the host.
+    unsafe {
+        proc.pre_exec(move || {
+            enter_namespaces(id_maps.as_ref(), network)?;
+            if let Some(x) = &stack {
+                x.mount_scratch()?;
+            }
+            run_steps(&targets)?;
+            if let Some(x) = &stack {
+                x.mount_overlay()?;
+            }
+            // pivot_root needs the new root to be a mount point. A linked
+            // root is made of hardlinks into the store, so it's read only
+            bind_mount_(&root, &root)?;
+            if stack.is_none() {
+                remount_read_only(&root)?;
+            }
+            run_steps(&mounts)?;
+            pivot_into(&root)?;
+            if let Err(e) = chdir(cwd.as_c_str()) {
+                if cwd_required {
+                    return Err(e.into());
+                }
+                chdir(c"/")?;
+            }
+            harden(backend, uid, gid, groups.as_deref(), filter.as_ref())
+        })
+    };
+    spawn_and_wait(proc)
+}
+
+/// Moves the calling process into a new mount namespace, along with a user
+/// namespace with id_maps if given, with mounts made on the host still
+/// propagating into them. Without network, it also gets a network namespace
+/// of its own. Meant to be called between fork and exec.
+fn enter_namespaces(
+    id_maps: Option<&IdMaps>,
+    network: bool,
+) -> std::io::Result<()> {
+    match id_maps {
+        Some(x) => userns::enter_user_namespace(x)?,
+        None => unshare(CloneFlags::CLONE_NEWNS)?,
+    }
+    if !network {
+        isolate_network()?;
+    }
+    nix::mount::mount(
+        Option::<&CStr>::None,
+        c"/",
+        Option::<&CStr>::None,
+        MsFlags::MS_SLAVE.union(MsFlags::MS_REC),
+        Option::<&CStr>::None,
+    )?;
+    Ok(())
+}
+
+/// The mounts stacking the layers of an environment with overlayfs, with
+/// their arguments built before forking.
+struct Stack {
+    /// Where the tmpfs holding the writable upper layer is mounted, so that
+    /// anything written inside of the environment is thrown away afterwards.
+    scratch: CString,
+    /// The upper, work and root directories inside of the tmpfs.
+    dirs: [CString; 3],
+    dpt_dir: CString,
+    options: CString,
+}
+
+impl Stack {
+    /// Plans stacking layers with the scratch directories in dir. The layers
+    /// are relative to the dpt directory, which the mount is made from, so
+    /// that long closures stay under the size limit of the options.
+    fn new(layers: &[PathBuf], dir: &Path, dpt_dir: &Path) -> Result<Self> {
+        let lower = layers
+            .iter()
+            .map(|x| {
+                x.strip_prefix(dpt_dir)
+                    .unwrap_or(x)
+                    .to_string_lossy()
+                    .replace(':', "\\:")
+            })
+            .collect::<Vec<String>>()
+            .join(":");
+        let options = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            lower,
+            dir.join("upper").display(),
+            dir.join("work").display()
+        );
+        Ok(Stack {
+            scratch: path_to_cstring(dir)?,
+            dirs: [
+                path_to_cstring(&dir.join("upper"))?,
+                path_to_cstring(&dir.join("work"))?,
+                path_to_cstring(&dir.join("root"))?,
+            ],
+            dpt_dir: path_to_cstring(dpt_dir)?,
+            options: CString::new(options)?,
+        })
+    }
+
+    /// Mounts the tmpfs with the directories of the overlay. Meant to be
+    /// called between fork and exec.
+    fn mount_scratch(&self) -> std::io::Result<()> {
+        nix::mount::mount(
+            Some(c"dpt"),
+            self.scratch.as_c_str(),
+            Some(c"tmpfs"),
+            MsFlags::empty(),
+            Some(c"mode=0755"),
+        )?;
+        for dir in &self.dirs {
+            mkdir(dir.as_c_str(), Mode::from_bits_truncate(0o777))?;
         }
-        if !dir.exists() {
-            continue;
+        Ok(())
+    }
+
+    /// Mounts the overlay at the root directory, after mount_scratch. Meant
+    /// to be called between fork and exec.
+    fn mount_overlay(&self) -> std::io::Result<()> {
+        chdir(self.dpt_dir.as_c_str())?;
+        nix::mount::mount(
+            Some(c"overlay"),
+            self.dirs[2].as_c_str(),
+            Some(c"overlay"),
+            MsFlags::empty(),
+            Some(self.options.as_c_str()),
+        )?;
+        Ok(())
+    }
+}
+
+/// Checks whether the layers can be stacked with overlayfs by mounting them
+/// in a short lived child process, as support depends on the kernel and on
+/// the filesystem of the store.
+fn overlay_available(
+    out_dir: &Path,
+    layers: &[PathBuf],
+    backend: Backend,
+    uid: u32,
+) -> bool {
+    let Ok(stack) = Stack::new(layers, out_dir, &get_dpt_dir()) else {
+        return false;
+    };
+    let id_maps = backend.id_maps(uid);
+    match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            let ok = enter_namespaces(id_maps.as_ref(), true).is_ok()
+                && stack.mount_scratch().is_ok()
+                && stack.mount_overlay().is_ok();
+            unsafe { libc::_exit(if ok { 0 } else { 1 }) }
         }
-        bind_mount(&dir, &dir_target)?;
-        binds.push(dir_target);
+        Ok(ForkResult::Parent { child }) => {
+            matches!(waitpid(child, None), Ok(WaitStatus::Exited(_, 0)))
+        }
+        Err(_) => false,
     }
+}
 
-    let mut cleanup = false;
+/// Makes root, which has to be a mount point, the root directory of the
+/// calling process, and detaches the old one.
+fn pivot_into(root: &CStr) -> std::io::Result<()> {
+    chdir(root)?;
+    pivot_root(c".", c".")?;
+    umount2(c".", MntFlags::MNT_DETACH)?;
+    chdir(c"/")?;
+    Ok(())
+}
 
-    let mut prefix = "/";
-    if out_dir.join("bin").join(&cmd).is_file()
-        || out_dir.join("bin").join(&cmd).is_symlink()
-    {
-        prefix = "/bin";
-    } else if out_dir.join("usr/bin").join(&cmd).is_file()
-        || out_dir.join("usr/bin").join(&cmd).is_symlink()
-    {
-        prefix = "/usr/bin";
-    } else {
-        error!("No executable found!");
-        cleanup = true;
-    }
-    let mut code: i32 = 0;
-    if !cleanup {
-        let mut proc = std::process::Command::new(std::env::current_exe()?);
-        let proc: Arc<Mutex<std::process::Child>> = Arc::new(Mutex::new(
-            proc.arg("chroot-not-intended-for-interactive-use")
-                .arg(&out_dir.to_str().ok_or(anyhow::anyhow!(
-                    "Failed to parse directory {} into string!",
-                    &out_dir.display()
-                ))?)
-                .arg(uid.to_string())
-                .arg(Path::new(prefix).join(&cmd))
-                .args(args)
-                .spawn()?,
-        ));
-        let proc_arc_clone = Arc::clone(&proc);
-        ctrlc::set_handler(move || {
-            let l = proc_arc_clone.lock();
-            if let Ok(mut x) = l {
-                let _ = x.kill();
-            };
-        })
-        .context("Failed to register ctrlc singal handler")?;
-        let l = proc.lock();
-        if let Ok(mut x) = l {
-            x.wait()?.code().unwrap_or(89);
-        } else {
-            code = 243;
+/// Looks for cmd in `/bin` and then `/usr/bin` of the directories making up
+/// the environment, returning its path inside of the environment. Absolute
+/// paths are only checked for existence.
+fn find_executable(dirs: &[PathBuf], cmd: &str) -> Option<PathBuf> {
+    if cmd.starts_with('/') {
+        let path = make_path_relative(Path::new(cmd));
+        return dirs
+            .iter()
+            .any(|x| x.join(&path).symlink_metadata().is_ok())
+            .then(|| PathBuf::from(cmd));
+    }
+    for prefix in ["bin", "usr/bin"] {
+        for dir in dirs {
+            let path = dir.join(prefix).join(cmd);
+            if path.is_file() || path.is_symlink() {
+                return Some(Path::new("/").join(prefix).join(cmd));
+            }
         }
     }
-    let mut binds2: Vec<PathBuf> = Vec::new();
-    let mut binds = binds;
-    binds.push(fpkg_target);
-
-    for _ in 0..10 {
-        for bind in &binds {
-            let e = unmount(&bind, UnmountFlags::DETACH);
-            if e.is_err() {
-                binds2.push(bind.clone());
-            } else {
-                if bind.is_dir() {
-                    if bind.read_dir()?.next().is_some() {
-                        for p in walkdir::WalkDir::new(&bind) {
-                            if let Ok(p) = p {
-                                let _ =
-                                    unmount(&p.path(), UnmountFlags::empty());
-                            }
-                        }
+    None
+}
+
+/// Finds what to run for cmd, looking through the exports of the packages
+/// before looking for an executable named cmd in the environment. Without
+/// cmd, the export of the first package named after it is used, then its
+/// first export, and then an executable named after it.
+fn find_command(
+    configs: &[PackageConfig],
+    layers: &[PathBuf],
+    cmd: Option<&str>,
+) -> Option<Export> {
+    let name = cmd.unwrap_or(&configs[0].name);
+    let mut exports = configs.iter().flat_map(|x| &x.exports);
+    if let Some(export) = exports.find(|x| x.name == name) {
+        return Some(export.clone());
+    }
+    if let (None, Some(export)) = (cmd, configs[0].exports.first()) {
+        return Some(export.clone());
+    }
+    find_executable(layers, name).map(|path| Export {
+        name: name.to_string(),
+        path,
+        args: Vec::new(),
+        env: Vec::new(),
+    })
+}
+
+/// Directories put in `PATH` when they exist in the environment.
+const PATH_DIRS: [&str; 6] = [
+    "/usr/local/bin",
+    "/usr/bin",
+    "/bin",
+    "/usr/local/sbin",
+    "/usr/sbin",
+    "/sbin",
+];
+
+/// Variables of the caller that only make sense on the host.
+const HOST_VARS: [&str; 6] = [
+    "LD_AUDIT",
+    "LD_LIBRARY_PATH",
+    "LD_PRELOAD",
+    "OLDPWD",
+    "PATH",
+    "PWD",
+];
+
+/// Variables of the caller kept with `--clear-env`.
+const KEPT_VARS: [&str; 5] = ["HOME", "LANG", "LOGNAME", "TERM", "USER"];
+
+/// Gets the `PATH` of the environment made of layers.
+fn default_path(layers: &[PathBuf]) -> String {
+    let dirs = PATH_DIRS
+        .into_iter()
+        .filter(|dir| {
+            layers.iter().any(|x| {
+                x.join(make_path_relative(Path::new(dir)))
+                    .symlink_metadata()
+                    .is_ok()
+            })
+        })
+        .collect::<Vec<&str>>();
+    if dirs.is_empty() {
+        return "/usr/bin:/bin".to_string();
+    }
+    dirs.join(":")
+}
 
-                        binds2.push(bind.clone());
+/// Builds the environment variables cmd runs with. The ones of the caller
+/// are kept, except for those pointing into the host, and then overridden by
+/// the ones of the packages, with the first package winning, of the command,
+/// and of options, in that order.
+fn build_environment(
+    caller: impl IntoIterator<Item = (String, String)>,
+    layers: &[PathBuf],
+    configs: &[PackageConfig],
+    cmd: &Export,
+    options: &RunOptions,
+) -> BTreeMap<String, String> {
+    let mut env = caller
+        .into_iter()
+        .filter(|(var, _)| !HOST_VARS.contains(&var.as_str()))
+        .filter(|(var, _)| {
+            !options.clear_env || KEPT_VARS.contains(&var.as_str())
+        })
+        .collect::<BTreeMap<String, String>>();
+    env.insert("PATH".to_string(), default_path(layers));
+    for config in configs.iter().rev() {
+        env.extend(config.env.iter().cloned());
+    }
+    env.extend(cmd.env.iter().cloned());
+    env.extend(options.env.iter().cloned());
+    env
+}
+
+/// Signals passed on to the package while it runs.
+const FORWARDED_SIGNALS: [Signal; 12] = [
+    Signal::SIGHUP,
+    Signal::SIGINT,
+    Signal::SIGQUIT,
+    Signal::SIGTERM,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+    Signal::SIGALRM,
+    Signal::SIGWINCH,
+    Signal::SIGCONT,
+    Signal::SIGTSTP,
+    Signal::SIGTTIN,
+    Signal::SIGTTOU,
+];
+
+/// Gets stdin if it's a terminal that dpt runs in the foreground of. The
+/// package then runs in a process group of its own, which is given the
+/// foreground, so that interactive shells can do job control in it.
+fn foreground_terminal() -> Option<BorrowedFd<'static>> {
+    // stdin stays open for as long as dpt runs
+    let stdin = unsafe { BorrowedFd::borrow_raw(libc::STDIN_FILENO) };
+    let foreground = isatty(libc::STDIN_FILENO).unwrap_or(false)
+        && tcgetpgrp(stdin) == Ok(getpgrp());
+    foreground.then_some(stdin)
+}
+
+/// Moves the foreground of terminal from the process group from to to, unless
+/// another one has it, like the calling shell after the job was put in the
+/// background. SIGTTOU is blocked, so this works from the background too.
+fn hand_over_terminal(terminal: BorrowedFd, from: Pid, to: Pid) {
+    if tcgetpgrp(terminal) == Ok(from) {
+        let _ = tcsetpgrp(terminal, to);
+    }
+}
+
+/// Runs proc and waits for it to exit, returning its exit code, or 128 plus
+/// the number of the signal that killed it.
+fn spawn_and_wait(mut proc: std::process::Command) -> Result<i32> {
+    let mut signals = SigSet::empty();
+    for sig in FORWARDED_SIGNALS {
+        signals.add(sig);
+    }
+    signals.add(Signal::SIGCHLD);
+    let terminal = foreground_terminal();
+    if terminal.is_some() {
+        proc.process_group(0);
+    }
+    // Blocked signals stay pending until they are waited for. The child
+    // would inherit the mask, so it gets the old one back before it execs.
+    // It takes the foreground itself as well, so that it never touches the
+    // terminal from the background if it gets there before dpt hands it over.
+    let old = signals.thread_swap_mask(SigmaskHow::SIG_BLOCK)?;
+    unsafe {
+        proc.pre_exec(move || {
+            if let Some(x) = terminal {
+                let _ = tcsetpgrp(x, getpid());
+            }
+            Ok(old.thread_set_mask()?)
+        });
+    }
+    let result = proc
+        .spawn()
+        .context("Failed to spawn package")
+        .and_then(|x| wait_forwarding_signals(&x, &signals, terminal));
+    old.thread_set_mask()?;
+    result
+}
+
+/// Waits for child to exit while forwarding signals to it. Signals from the
+/// terminal are sent to the foreground process group by the kernel, so they
+/// aren't forwarded again. When the package is stopped, dpt stops itself too
+/// so that the job control of the calling shell notices, and continues the
+/// package once it is continued. With a terminal, the package runs in a
+/// process group of its own, which has the foreground of the terminal
+/// whenever dpt would have it.
+fn wait_forwarding_signals(
+    child: &std::process::Child,
+    signals: &SigSet,
+    terminal: Option<BorrowedFd>,
+) -> Result<i32> {
+    let child = Pid::from_raw(child.id() as i32);
+    if let Some(x) = terminal {
+        // The child might not have gotten to it yet
+        let _ = setpgid(child, child);
+        hand_over_terminal(x, getpgrp(), child);
+    }
+    let give_back = || {
+        if let Some(x) = terminal {
+            hand_over_terminal(x, child, getpgrp());
+        }
+    };
+    loop {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let sig = unsafe { libc::sigwaitinfo(signals.as_ref(), &mut info) };
+        if sig < 0 {
+            match Errno::last() {
+                Errno::EINTR => continue,
+                e => {
+                    give_back();
+                    return Err(e).context("Failed to wait for signals");
+                }
+            }
+        }
+        let sig = Signal::try_from(sig)?;
+        if sig != Signal::SIGCHLD {
+            if info.si_code != libc::SI_KERNEL {
+                let _ = kill(child, sig);
+            }
+            continue;
+        }
+        loop {
+            let flags = WaitPidFlag::WNOHANG | WaitPidFlag::WUNTRACED;
+            match waitpid(child, Some(flags))? {
+                WaitStatus::Exited(_, code) => {
+                    give_back();
+                    return Ok(code);
+                }
+                WaitStatus::Signaled(_, sig, _) => {
+                    give_back();
+                    return Ok(128 + sig as i32);
+                }
+                WaitStatus::Stopped(..) => {
+                    give_back();
+                    kill(getpid(), Signal::SIGSTOP)?;
+                    match terminal {
+                        Some(x) => {
+                            hand_over_terminal(x, getpgrp(), child);
+                            let _ = killpg(child, Signal::SIGCONT);
+                        }
+                        None => {
+                            let _ = kill(child, Signal::SIGCONT);
+                        }
                     }
                 }
+                _ => break,
             }
         }
-        binds = binds2.clone();
-        binds2 = Vec::new();
     }
-
-    assert!(binds2.is_empty(), "Terminated to prevent data loss");
-
-    Ok(code)
 }
 
 pub fn run_multiple_packages(
@@ -211,38 +654,159 @@ pub fn run_multiple_packages(
     uid: u32,
     args: Vec<String>,
     cmd: Option<&str>,
+    options: &RunOptions,
     allow_non_dpt_file: bool,
 ) -> Result<i32> {
     if pkgs.is_empty() {
         bail!("No packages specified!");
     }
 
-    let mut pkg_path = get_run_location().join(get_random_string(10));
-    while pkg_path.exists() {
-        pkg_path = get_run_location().join(get_random_string(10));
-    }
-    let pkg_path = pkg_path;
+    let backend = select_backend(uid)?;
+    let (run_location, mode) = match backend {
+        // Immutable files can't be hardlinked to
+        Backend::Setuid => match store_is_immutable()? {
+            true => (get_run_location(), LinkMode::Symlink),
+            false => (get_run_location(), LinkMode::Hardlink),
+        },
+        Backend::Rootless { .. } => {
+            (userns::get_user_run_location(uid)?, LinkMode::Symlink)
+        }
+    };
 
-    let mut done_list: Vec<Package> = Vec::new();
     let installed_packages = if allow_non_dpt_file == false {
         get_installed_packages()?
     } else {
         get_installed_packages_without_dpt_file()?
     };
+    let layers = get_environment_layers(pkgs, &installed_packages)?;
 
-    for pkg in pkgs {
-        crate::env::generate_environment_for_package(
-            pkg,
-            &installed_packages,
-            &pkg_path,
-            &mut done_list,
-        )?;
+    let env = get_cached_environment(&run_location, &layers, mode, |dir| {
+        let stacked = layers_can_be_stacked(&layers)
+            && overlay_available(dir, &layers, backend, uid);
+        if !stacked {
+            debug!("Can't use overlayfs, linking the environment instead");
+        }
+        stacked
+    })?;
+
+    if !env.conflicts.is_empty() && conflicts_are_strict()? {
+        for conflict in &env.conflicts {
+            error!("{}", conflict);
+        }
+        bail!("Refusing to run with conflicting files, as strict-conflicts is set!");
     }
 
-    let cmd = cmd.unwrap_or(&pkgs[0].name);
+    let sandbox = get_sandbox(&pkgs[0], &installed_packages)?;
+
+    let configs = pkgs
+        .iter()
+        .map(|x| {
+            let dir = package_to_onlinepackage(x, &installed_packages)?.url;
+            read_package_config(Path::new(&dir))
+        })
+        .collect::<Result<Vec<PackageConfig>>>()?;
+    let Some(cmd) = find_command(&configs, &layers, cmd) else {
+        error!("No executable found!");
+        return Ok(127);
+    };
+
+    // Nothing is inherited, the whole environment is passed explicitly
+    let caller = std::env::vars_os().filter_map(|(var, value)| {
+        Some((var.into_string().ok()?, value.into_string().ok()?))
+    });
+    let mut proc = std::process::Command::new(&cmd.path);
+    proc.args(&cmd.args)
+        .args(args)
+        .env_clear()
+        .envs(build_environment(caller, &layers, &configs, &cmd, options));
 
-    let code = run_pkg_(&pkg_path, uid, args, cmd)?;
-    std::fs::remove_dir_all(pkg_path)?;
+    let code = run_pkg_(
+        &env.dir,
+        &layers,
+        env.stacked,
+        Invocation {
+            proc,
+            uid,
+            backend,
+            sandbox: &sandbox,
+            cwd: options.cwd.as_deref(),
+        },
+    )?;
 
     Ok(code)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment() {
+        let dir = std::env::temp_dir()
+            .join(format!("dpt-run-env-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("usr/bin")).unwrap();
+        std::fs::create_dir_all(dir.join("usr/sbin")).unwrap();
+        let layers = vec![dir.clone()];
+
+        let caller = || {
+            [
+                ("PATH", "/home/john/.cargo/bin:/usr/bin"),
+                ("LD_LIBRARY_PATH", "/opt/lib"),
+                ("HOME", "/home/john"),
+                ("EDITOR", "vi"),
+                ("THEME", "light"),
+            ]
+            .map(|(var, value)| (var.to_string(), value.to_string()))
+        };
+        let pair =
+            |var: &str, value: &str| (var.to_string(), value.to_string());
+        let mut configs = vec![PackageConfig {
+            name: "foo".to_string(),
+            version: "1.0.0".to_string(),
+            depends: vec![],
+            provides_plugs: vec![],
+            plugs: vec![],
+            exports: vec![],
+            env: vec![pair("THEME", "dark"), pair("FOO", "1")],
+            conflicts: vec![],
+            replaces: vec![],
+        }];
+        configs.push(PackageConfig {
+            name: "bar".to_string(),
+            env: vec![pair("THEME", "bar"), pair("BAR", "1")],
+            ..configs[0].clone()
+        });
+        let cmd = Export {
+            name: "foo".to_string(),
+            path: PathBuf::from("/usr/bin/foo"),
+            args: vec![],
+            env: vec![pair("FOO", "2")],
+        };
+        let mut options = RunOptions {
+            env: vec![pair("BAR", "2")],
+            ..Default::default()
+        };
+
+        let env =
+            build_environment(caller(), &layers, &configs, &cmd, &options);
+        assert_eq!(
+            env,
+            BTreeMap::from([
+                pair("BAR", "2"),
+                pair("EDITOR", "vi"),
+                pair("FOO", "2"),
+                pair("HOME", "/home/john"),
+                pair("PATH", "/usr/bin:/usr/sbin"),
+                pair("THEME", "dark"),
+            ])
+        );
+
+        options.clear_env = true;
+        let env =
+            build_environment(caller(), &layers, &configs, &cmd, &options);
+        assert!(!env.contains_key("EDITOR"));
+        assert_eq!(env["HOME"], "/home/john");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,742 @@
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use kdl::{KdlDocument, KdlNode};
+use nix::{
+    errno::Errno,
+    fcntl::{open, OFlag},
+    libc,
+    mount::MsFlags,
+    sched::{unshare, CloneFlags},
+    sys::{
+        stat::Mode,
+        statvfs::{statvfs, FsFlags},
+    },
+    unistd::{access, close, mkdir, symlinkat, AccessFlags},
+};
+
+use crate::{
+    dpt_file::{get_dpt_lock_location, read_dpt_lock_file},
+    pkg::{parse_kdl, Package},
+    repo::{package_to_onlinepackage, OnlinePackage},
+    run::{bind_mount_, make_path_relative, path_to_cstring},
+};
+
+/// A host path made visible inside of an environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bind {
+    pub path: PathBuf,
+    pub read_only: bool,
+}
+
+/// A seccomp filter denying system calls that packages have no use for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Seccomp {
+    /// Installs no filter.
+    Off,
+    /// Denies administering the system and leaving the sandbox, like
+    /// mounting, loading kernel modules and entering namespaces.
+    Default,
+    /// Also denies tracing other processes and changing the clock or host
+    /// name.
+    Strict,
+}
+
+/// What a package gets to see from the host when it is run. Options that
+/// aren't set keep their defaults, which match running without a profile.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sandbox {
+    /// Host paths bound at the same path, after the default ones.
+    pub binds: Vec<Bind>,
+    /// Whether `/home` is bound. Defaults to true.
+    pub home: Option<bool>,
+    /// Whether `/tmp` is an empty tmpfs instead of the host's. Defaults to
+    /// false.
+    pub private_tmp: Option<bool>,
+    /// Whether the host's network is available. Defaults to true.
+    pub network: Option<bool>,
+    /// The devices available besides basic ones like `/dev/null`. All of the
+    /// host's devices are available when unset.
+    pub devices: Option<Vec<PathBuf>>,
+    /// The seccomp filter to install. Defaults to off.
+    pub seccomp: Option<Seccomp>,
+}
+
+impl Sandbox {
+    /// Applies the options set in overrides on top of this profile. Binds and
+    /// devices are added to the ones already there.
+    pub fn merge(mut self, overrides: &Sandbox) -> Sandbox {
+        self.binds.extend(overrides.binds.iter().cloned());
+        self.home = overrides.home.or(self.home);
+        self.private_tmp = overrides.private_tmp.or(self.private_tmp);
+        self.network = overrides.network.or(self.network);
+        self.seccomp = overrides.seccomp.or(self.seccomp);
+        if let Some(devices) = &overrides.devices {
+            self.devices
+                .get_or_insert_with(Vec::new)
+                .extend(devices.iter().cloned());
+        }
+        self
+    }
+
+    pub fn home(&self) -> bool {
+        self.home.unwrap_or(true)
+    }
+
+    pub fn private_tmp(&self) -> bool {
+        self.private_tmp.unwrap_or(false)
+    }
+
+    pub fn network(&self) -> bool {
+        self.network.unwrap_or(true)
+    }
+
+    pub fn seccomp(&self) -> Seccomp {
+        self.seccomp.unwrap_or(Seccomp::Off)
+    }
+}
+
+fn get_bool_arg(node: &KdlNode) -> Result<bool> {
+    node.get(0).and_then(|x| x.as_bool()).ok_or(anyhow!(
+        "`{}` in sandbox profile needs to be #true or #false",
+        node.name().value()
+    ))
+}
+
+fn get_path_arg(node: &KdlNode) -> Result<PathBuf> {
+    let path = node.get(0).and_then(|x| x.as_string()).ok_or(anyhow!(
+        "`{}` in sandbox profile needs a path",
+        node.name().value()
+    ))?;
+    if !path.starts_with('/') {
+        bail!("Path `{}` in sandbox profile is not absolute!", path);
+    }
+    Ok(PathBuf::from(path))
+}
+
+/// Parses the children of a `sandbox` node.
+pub fn parse_sandbox(node: &KdlNode) -> Result<Sandbox> {
+    let mut sandbox = Sandbox::default();
+    let Some(children) = node.children() else {
+        return Ok(sandbox);
+    };
+    for option in children.nodes() {
+        match option.name().value() {
+            "bind" | "bind-ro" => sandbox.binds.push(Bind {
+                path: get_path_arg(option)?,
+                read_only: option.name().value() == "bind-ro",
+            }),
+            "home" => sandbox.home = Some(get_bool_arg(option)?),
+            "private-tmp" => sandbox.private_tmp = Some(get_bool_arg(option)?),
+            "network" => sandbox.network = Some(get_bool_arg(option)?),
+            "device" => {
+                let path = get_path_arg(option)?;
+                if !path.starts_with("/dev")
+                    || path.components().any(|x| x == Component::ParentDir)
+                {
+                    bail!("Device {} is not in /dev!", path.display());
+                }
+                sandbox.devices.get_or_insert_with(Vec::new).push(path);
+            }
+            "seccomp" => {
+                let filter = option.get(0).and_then(|x| x.as_string());
+                sandbox.seccomp = Some(match filter {
+                    Some("none") => Seccomp::Off,
+                    Some("default") => Seccomp::Default,
+                    Some("strict") => Seccomp::Strict,
+                    _ => bail!(
+                        "`seccomp` in sandbox profile needs to be \"none\", \"default\" or \"strict\""
+                    ),
+                });
+            }
+            x => bail!("Unknown sandbox option `{}`!", x),
+        }
+    }
+    Ok(sandbox)
+}
+
+/// Parses the sandbox profile of a package configuration.
+pub fn parse_package_sandbox(doc: &KdlDocument) -> Result<Sandbox> {
+    match doc.get("sandbox") {
+        Some(x) => parse_sandbox(x),
+        None => Ok(Sandbox::default()),
+    }
+}
+
+/// Parses the per package overrides in the `sandbox` node of a dpt file.
+pub fn parse_sandbox_overrides(
+    doc: &KdlDocument,
+) -> Result<HashMap<String, Sandbox>> {
+    let mut overrides = HashMap::new();
+    let Some(children) = doc.get("sandbox").and_then(|x| x.children()) else {
+        return Ok(overrides);
+    };
+    for node in children.nodes() {
+        overrides.insert(node.name().value().to_string(), parse_sandbox(node)?);
+    }
+    Ok(overrides)
+}
+
+/// Gets the sandbox profile of an installed package, with the overrides for
+/// it from `dpt.lock` applied.
+pub fn get_sandbox(
+    pkg: &Package,
+    installed: &Vec<OnlinePackage>,
+) -> Result<Sandbox> {
+    let pkg_dir = PathBuf::from(package_to_onlinepackage(pkg, installed)?.url);
+    let config = pkg_dir.join("dpt").join("pkg.kdl");
+    let sandbox = parse_package_sandbox(&parse_kdl(
+        &std::fs::read_to_string(&config)
+            .context(anyhow!("Failed to read {}", config.display()))?,
+    )?)
+    .context(anyhow!("Invalid sandbox profile in {}", config.display()))?;
+
+    if !get_dpt_lock_location().exists() {
+        return Ok(sandbox);
+    }
+    Ok(match read_dpt_lock_file()?.sandbox.get(&pkg.name) {
+        Some(overrides) => sandbox.merge(overrides),
+        None => sandbox,
+    })
+}
+
+/// A step in setting up the filesystem of an environment. Targets are
+/// relative to its root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mount {
+    Bind {
+        src: PathBuf,
+        target: PathBuf,
+        read_only: bool,
+    },
+    Tmpfs {
+        target: PathBuf,
+        mode: u32,
+    },
+    Symlink {
+        target: PathBuf,
+        to: PathBuf,
+    },
+}
+
+impl Mount {
+    pub fn target(&self) -> &Path {
+        match self {
+            Mount::Bind { target, .. } => target,
+            Mount::Tmpfs { target, .. } => target,
+            Mount::Symlink { target, .. } => target,
+        }
+    }
+
+    /// Builds the steps creating the target of the mount under dir.
+    fn target_steps(&self, dir: &Path) -> Result<Vec<MountStep>> {
+        let target = dir.join(self.target());
+        let mut steps = Vec::<MountStep>::new();
+        match self {
+            Mount::Bind { src, .. } if !src.is_dir() => {
+                if let Some(parent) = target.parent() {
+                    steps.extend(mkdir_steps(dir, parent)?);
+                }
+                steps.push(MountStep::Touch(path_to_cstring(&target)?));
+            }
+            Mount::Bind { .. } | Mount::Tmpfs { .. } => {
+                steps.extend(mkdir_steps(dir, &target)?)
+            }
+            Mount::Symlink { to, .. } => steps.push(MountStep::Symlink {
+                to: path_to_cstring(to)?,
+                at: path_to_cstring(&target)?,
+            }),
+        }
+        Ok(steps)
+    }
+
+    /// Builds the step making the mount under root, if it needs one.
+    fn mount_step(&self, root: &Path) -> Result<Option<MountStep>> {
+        let target = path_to_cstring(&root.join(self.target()))?;
+        Ok(match self {
+            Mount::Bind { src, read_only, .. } => Some(MountStep::Bind {
+                src: path_to_cstring(src)?,
+                target,
+                read_only: *read_only,
+            }),
+            Mount::Tmpfs { mode, .. } => Some(MountStep::Tmpfs {
+                target,
+                options: CString::new(format!("mode={:o}", mode))?,
+            }),
+            Mount::Symlink { .. } => None,
+        })
+    }
+}
+
+/// A system call setting up the filesystem of an environment, with its
+/// arguments built ahead of time, as it's made between fork and exec where
+/// allocating isn't safe.
+#[derive(Debug)]
+pub enum MountStep {
+    /// Creates a directory, unless something is already there.
+    Mkdir(CString),
+    /// Creates an empty file to mount on, unless something is already there,
+    /// as files of the environment can be hardlinks into the store.
+    Touch(CString),
+    Symlink {
+        to: CString,
+        at: CString,
+    },
+    Bind {
+        src: CString,
+        target: CString,
+        read_only: bool,
+    },
+    Tmpfs {
+        target: CString,
+        options: CString,
+    },
+}
+
+impl MountStep {
+    fn run(&self) -> io::Result<()> {
+        match self {
+            MountStep::Mkdir(path) => {
+                match mkdir(path.as_c_str(), Mode::from_bits_truncate(0o777)) {
+                    Err(Errno::EEXIST) => {}
+                    x => x?,
+                }
+            }
+            MountStep::Touch(path) => {
+                if access(path.as_c_str(), AccessFlags::F_OK).is_err() {
+                    let fd = open(
+                        path.as_c_str(),
+                        OFlag::O_CREAT | OFlag::O_WRONLY | OFlag::O_CLOEXEC,
+                        Mode::from_bits_truncate(0o666),
+                    )?;
+                    close(fd)?;
+                }
+            }
+            MountStep::Symlink { to, at } => {
+                match symlinkat(to.as_c_str(), None, at.as_c_str()) {
+                    Err(Errno::EEXIST) => {}
+                    x => x?,
+                }
+            }
+            MountStep::Bind {
+                src,
+                target,
+                read_only,
+            } => {
+                bind_mount_(src, target)?;
+                if *read_only {
+                    remount_read_only(target)?;
+                }
+            }
+            MountStep::Tmpfs { target, options } => nix::mount::mount(
+                Some(c"dpt"),
+                target.as_c_str(),
+                Some(c"tmpfs"),
+                MsFlags::MS_NOSUID.union(MsFlags::MS_NODEV),
+                Some(options.as_c_str()),
+            )?,
+        }
+        Ok(())
+    }
+}
+
+/// Builds the steps creating path along with its parents below dir, which
+/// has to exist.
+fn mkdir_steps(dir: &Path, path: &Path) -> Result<Vec<MountStep>> {
+    let mut dirs = path
+        .ancestors()
+        .take_while(|x| *x != dir && x.starts_with(dir))
+        .collect::<Vec<&Path>>();
+    dirs.reverse();
+    dirs.into_iter()
+        .map(|x| Ok(MountStep::Mkdir(path_to_cstring(x)?)))
+        .collect()
+}
+
+/// Makes a bind mount read only. The flags that a user namespace isn't
+/// allowed to clear are kept.
+pub fn remount_read_only(target: &CStr) -> io::Result<()> {
+    let current = statvfs(target)?.flags();
+    let mut flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+    for (fs_flag, ms_flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+    ] {
+        if current.contains(fs_flag) {
+            flags.insert(ms_flag);
+        }
+    }
+    nix::mount::mount(
+        Option::<&CStr>::None,
+        target,
+        Option::<&CStr>::None,
+        flags,
+        Option::<&CStr>::None,
+    )?;
+    Ok(())
+}
+
+/// Gets the mounts for a `/dev` with only basic devices and the given ones.
+fn device_mounts(devices: &[PathBuf]) -> Vec<Mount> {
+    let mut mounts = vec![Mount::Tmpfs {
+        target: PathBuf::from("dev"),
+        mode: 0o755,
+    }];
+    let basic = ["null", "zero", "full", "random", "urandom", "tty", "ptmx"]
+        .iter()
+        .chain(["pts"].iter())
+        .map(|x| Path::new("/dev").join(x));
+    for src in basic.chain(devices.iter().cloned()) {
+        if src.exists() {
+            mounts.push(Mount::Bind {
+                target: make_path_relative(&src),
+                src,
+                read_only: false,
+            });
+        }
+    }
+    mounts.push(Mount::Tmpfs {
+        target: PathBuf::from("dev/shm"),
+        mode: 0o1777,
+    });
+    for (name, to) in [
+        ("fd", "/proc/self/fd"),
+        ("stdin", "/proc/self/fd/0"),
+        ("stdout", "/proc/self/fd/1"),
+        ("stderr", "/proc/self/fd/2"),
+    ] {
+        mounts.push(Mount::Symlink {
+            target: Path::new("dev").join(name),
+            to: PathBuf::from(to),
+        });
+    }
+    mounts
+}
+
+/// Plans the mounts for an environment made of layers, following the
+/// sandbox profile. The store, and run_dir with the environments linked into
+/// it, are read only wherever they show up.
+pub fn plan_mounts(
+    sandbox: &Sandbox,
+    layers: &[PathBuf],
+    dpt_dir: &Path,
+    run_dir: &Path,
+) -> Vec<Mount> {
+    let mut mounts = Vec::<Mount>::new();
+    for bind in ["dev", "mnt", "media", "run", "var", "home", "tmp", "proc"] {
+        let dir = Path::new("/").join(bind);
+        if bind == "tmp" && sandbox.private_tmp() {
+            mounts.push(Mount::Tmpfs {
+                target: PathBuf::from(bind),
+                mode: 0o1777,
+            });
+            continue;
+        }
+        if layers.iter().any(|x| x.join(bind).exists()) || !dir.exists() {
+            continue;
+        }
+        if bind == "home" && !sandbox.home() {
+            continue;
+        }
+        match (bind, &sandbox.devices) {
+            ("dev", Some(devices)) => mounts.extend(device_mounts(devices)),
+            _ => mounts.push(Mount::Bind {
+                src: dir,
+                target: PathBuf::from(bind),
+                read_only: false,
+            }),
+        }
+    }
+
+    // Bind mount dpt dir inside the environment
+    mounts.push(Mount::Bind {
+        src: dpt_dir.to_path_buf(),
+        target: make_path_relative(dpt_dir),
+        read_only: false,
+    });
+
+    for bind in &sandbox.binds {
+        mounts.push(Mount::Bind {
+            src: bind.path.clone(),
+            target: make_path_relative(&bind.path),
+            read_only: bind.read_only,
+        });
+    }
+
+    // Packages sharing the store mustn't be able to change each other, which
+    // they could through the hardlinks of linked environments too. These go
+    // last, so that none of the binds above make them writable again.
+    for dir in [dpt_dir.join("store"), run_dir.to_path_buf()] {
+        let visible = mounts.iter().any(
+            |x| matches!(x, Mount::Bind { src, .. } if dir.starts_with(src)),
+        );
+        if visible {
+            mounts.push(Mount::Bind {
+                target: make_path_relative(&dir),
+                src: dir,
+                read_only: true,
+            });
+        }
+    }
+    mounts
+}
+
+/// Checks whether the target of `mounts[i]` is inside of a tmpfs mounted by
+/// an earlier one, so that it can only be created after that is mounted.
+fn in_tmpfs(mounts: &[Mount], i: usize) -> bool {
+    mounts[..i].iter().any(|x| {
+        matches!(x, Mount::Tmpfs { .. })
+            && mounts[i].target().starts_with(x.target())
+    })
+}
+
+/// Builds the steps creating the targets of the mounts under dir, except for
+/// the ones inside of a tmpfs, which can only be created once it's mounted.
+pub fn target_steps(mounts: &[Mount], dir: &Path) -> Result<Vec<MountStep>> {
+    let mut steps = Vec::<MountStep>::new();
+    for (i, mount) in mounts.iter().enumerate() {
+        if !in_tmpfs(mounts, i) {
+            steps.extend(mount.target_steps(dir)?);
+        }
+    }
+    Ok(steps)
+}
+
+/// Builds the steps making the mounts under root, after the ones of
+/// target_steps.
+pub fn mount_steps(mounts: &[Mount], root: &Path) -> Result<Vec<MountStep>> {
+    let mut steps = Vec::<MountStep>::new();
+    for (i, mount) in mounts.iter().enumerate() {
+        if in_tmpfs(mounts, i) {
+            steps.extend(mount.target_steps(root)?);
+        }
+        steps.extend(mount.mount_step(root)?);
+    }
+    Ok(steps)
+}
+
+/// Runs steps in order. Meant to be called between fork and exec.
+pub fn run_steps(steps: &[MountStep]) -> io::Result<()> {
+    for step in steps {
+        step.run()?;
+    }
+    Ok(())
+}
+
+/// Moves the calling process into a new network namespace, with only the
+/// loopback interface up. Meant to be called between fork and exec.
+pub fn isolate_network() -> io::Result<()> {
+    unshare(CloneFlags::CLONE_NEWNET)?;
+    unsafe {
+        let sock = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+        if sock < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut req: libc::ifreq = std::mem::zeroed();
+        req.ifr_name[0] = b'l' as libc::c_char;
+        req.ifr_name[1] = b'o' as libc::c_char;
+        let mut res = libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut req);
+        if res == 0 {
+            req.ifr_ifru.ifru_flags |=
+                (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+            res = libc::ioctl(sock, libc::SIOCSIFFLAGS, &req);
+        }
+        let err = io::Error::last_os_error();
+        libc::close(sock);
+        if res < 0 {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_merge_sandbox() {
+        let pkg = parse_kdl(
+            r#"
+name "foo"
+version "1.0.0"
+sandbox {
+    home #false
+    network #false
+    bind-ro "/etc/resolv.conf"
+    device "/dev/dri"
+    seccomp "strict"
+}
+"#,
+        )
+        .unwrap();
+        let dpt = parse_kdl(
+            r#"
+sandbox {
+    foo {
+        network #true
+        seccomp "none"
+        bind "/srv"
+        device "/dev/snd"
+    }
+}
+"#,
+        )
+        .unwrap();
+        let sandbox = parse_package_sandbox(&pkg).unwrap();
+        assert!(!sandbox.home());
+        assert!(!sandbox.network());
+        assert!(!sandbox.private_tmp());
+        assert_eq!(sandbox.seccomp(), Seccomp::Strict);
+
+        let overrides = parse_sandbox_overrides(&dpt).unwrap();
+        let sandbox = sandbox.merge(&overrides["foo"]);
+        assert!(sandbox.network());
+        assert!(!sandbox.home());
+        assert_eq!(sandbox.seccomp(), Seccomp::Off);
+        assert_eq!(
+            sandbox.binds,
+            vec![
+                Bind {
+                    path: PathBuf::from("/etc/resolv.conf"),
+                    read_only: true
+                },
+                Bind {
+                    path: PathBuf::from("/srv"),
+                    read_only: false
+                }
+            ]
+        );
+        assert_eq!(
+            sandbox.devices,
+            Some(vec![PathBuf::from("/dev/dri"), PathBuf::from("/dev/snd")])
+        );
+    }
+
+    #[test]
+    fn invalid_sandbox() {
+        for s in [
+            "sandbox { home \"yes\" }",
+            "sandbox { bind \"relative\" }",
+            "sandbox { device \"/etc/passwd\" }",
+            "sandbox { device \"/dev/../etc/shadow\" }",
+            "sandbox { device \"/dev/../x\" }",
+            "sandbox { unknown #true }",
+            "sandbox { seccomp \"loose\" }",
+        ] {
+            parse_package_sandbox(&parse_kdl(s).unwrap()).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn mounts_in_tmpfs() {
+        let sandbox = Sandbox {
+            private_tmp: Some(true),
+            ..Default::default()
+        };
+        let mounts = plan_mounts(
+            &sandbox,
+            &[],
+            Path::new("/tmp/dpt"),
+            Path::new("/tmp/dpt/run"),
+        );
+        let tmp = mounts
+            .iter()
+            .position(|x| x.target() == Path::new("tmp"))
+            .unwrap();
+        let dpt = mounts
+            .iter()
+            .position(|x| x.target() == Path::new("tmp/dpt"))
+            .unwrap();
+        assert!(tmp < dpt);
+        assert!(in_tmpfs(&mounts, dpt));
+        assert!(!in_tmpfs(&mounts, tmp));
+        assert!(mounts.iter().any(|x| matches!(
+            x,
+            Mount::Bind { target, read_only: true, .. }
+                if target == Path::new("tmp/dpt/store")
+        )));
+    }
+
+    #[test]
+    fn store_stays_read_only() {
+        let sandbox = parse_package_sandbox(
+            &parse_kdl("sandbox { bind \"/dpt\"; bind \"/dpt/store\" }")
+                .unwrap(),
+        )
+        .unwrap();
+        let read_only = |mounts: &[Mount], dir: &str| {
+            let last = mounts
+                .iter()
+                .rposition(|x| Path::new(dir).starts_with(x.target()));
+            last.is_some_and(|i| {
+                matches!(
+                    mounts[i],
+                    Mount::Bind {
+                        read_only: true,
+                        ..
+                    }
+                ) && mounts[i].target() == Path::new(dir)
+            })
+        };
+
+        let mounts = plan_mounts(
+            &sandbox,
+            &[],
+            Path::new("/dpt"),
+            Path::new("/dpt/run"),
+        );
+        assert!(read_only(&mounts, "dpt/store"));
+        assert!(read_only(&mounts, "dpt/run"));
+
+        // Not reachable from the environment, so not bound at all
+        let mounts = plan_mounts(
+            &Sandbox::default(),
+            &[],
+            Path::new("/dpt"),
+            Path::new("/nonexistent/run"),
+        );
+        assert!(read_only(&mounts, "dpt/store"));
+        assert!(!mounts
+            .iter()
+            .any(|x| x.target() == Path::new("nonexistent/run")));
+    }
+
+    #[test]
+    fn bind_targets_keep_files() {
+        let dir = std::env::temp_dir()
+            .join(format!("dpt-sandbox-targets-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::write(root.join("etc/resolv.conf"), "nameserver").unwrap();
+        std::fs::write(dir.join("file"), "").unwrap();
+
+        let bind = |src: &Path, target: &str| Mount::Bind {
+            src: src.to_path_buf(),
+            target: PathBuf::from(target),
+            read_only: true,
+        };
+        let mounts = [
+            bind(&dir.join("file"), "etc/resolv.conf"),
+            bind(&dir.join("file"), "etc/ssl/cert.pem"),
+            bind(&dir, "srv/data"),
+        ];
+        run_steps(&target_steps(&mounts, &root).unwrap()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("etc/resolv.conf")).unwrap(),
+            "nameserver"
+        );
+        assert!(root.join("etc/ssl/cert.pem").is_file());
+        assert!(root.join("srv/data").is_dir());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
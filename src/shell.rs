@@ -0,0 +1,91 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::info;
+
+use crate::{
+    env::get_environment_layers,
+    exec::read_environment_file,
+    pkg::{Package, PackageConfig},
+    run::{make_path_relative, run_multiple_packages, RunOptions},
+    store::{
+        get_installed_packages, get_installed_packages_without_dpt_file,
+        read_package_config,
+    },
+};
+
+/// Gets the login shell of uid from the `/etc/passwd` of the environment made
+/// of layers, if the environment has it.
+fn user_shell(layers: &[PathBuf], uid: u32) -> Option<String> {
+    let passwd = read_environment_file(layers, "passwd")?;
+    let shell = passwd
+        .lines()
+        .map(|l| l.split(':').collect::<Vec<&str>>())
+        .find(|f| f.len() > 6 && f[2] == uid.to_string())
+        .map(|f| f[6].to_string())?;
+    let path = make_path_relative(Path::new(&shell));
+    layers
+        .iter()
+        .any(|x| x.join(&path).symlink_metadata().is_ok())
+        .then_some(shell)
+}
+
+/// Gets a prompt marking that shell runs inside of the environment of
+/// names, in the syntax of the shell.
+fn shell_prompt(shell: &str, names: &str) -> String {
+    match Path::new(shell).file_name().and_then(|x| x.to_str()) {
+        Some("bash") => format!("(dpt: {}) \\w \\$ ", names),
+        Some("zsh") => format!("(dpt: {}) %~ %# ", names),
+        _ => format!("(dpt: {}) $ ", names),
+    }
+}
+
+/// Starts the shell of the user, or `bash`, in the environment of pkgs,
+/// after listing the packages that are in it. The prompt shows that the
+/// shell is inside of dpt, unless the shell's startup files set their own.
+pub fn run_shell(
+    pkgs: &Vec<Package>,
+    uid: u32,
+    args: Vec<String>,
+    mut options: RunOptions,
+    allow_non_dpt_file: bool,
+) -> Result<i32> {
+    let installed = if allow_non_dpt_file {
+        get_installed_packages_without_dpt_file()?
+    } else {
+        get_installed_packages()?
+    };
+    let layers = get_environment_layers(pkgs, &installed)?;
+    let configs = layers
+        .iter()
+        .filter(|x| x.join("dpt/pkg.kdl").is_file())
+        .map(|x| read_package_config(x))
+        .collect::<Result<Vec<PackageConfig>>>()?;
+    info!("Packages in the environment:");
+    for config in &configs {
+        info!("  {} {}", config.name, config.version);
+    }
+
+    let shell = user_shell(&layers, uid).unwrap_or("bash".to_string());
+    let names = pkgs
+        .iter()
+        .map(|x| x.name.as_str())
+        .collect::<Vec<&str>>()
+        .join(" ");
+    // Given first, so that `--env` can override them
+    options.env.splice(
+        0..0,
+        [
+            ("PS1".to_string(), shell_prompt(&shell, &names)),
+            ("DPT_SHELL".to_string(), names),
+        ],
+    );
+    run_multiple_packages(
+        pkgs,
+        uid,
+        args,
+        Some(&shell),
+        &options,
+        allow_non_dpt_file,
+    )
+}
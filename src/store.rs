@@ -1,11 +1,20 @@
-use std::fs;
-use std::path::PathBuf;
+use std::fs::{self, File, Permissions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use crate::dpt_file::read_dpt_lock_file;
-use crate::pkg::{get_package_config, Package};
+use crate::dpt_file::{get_dpt_lock_location, read_dpt_lock_file};
+use crate::pkg::{get_package_config, Package, PackageConfig};
 use crate::repo::OnlinePackage;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use nix::libc;
+use walkdir::WalkDir;
+
+/// The inode flag of `FS_IOC_SETFLAGS` that makes it immutable, from
+/// `linux/fs.h`.
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
 
 pub fn get_dpt_dir() -> PathBuf {
     if let Ok(x) = fs::read_to_string("/etc/dpt/dir") {
@@ -20,6 +29,20 @@ pub fn get_store_location() -> PathBuf {
     get_dpt_dir().join("store")
 }
 
+/// Gets the directory of a package in the store.
+pub fn get_package_dir(pkg: &Package) -> PathBuf {
+    get_store_location().join(format!("{}-{}", pkg.name, pkg.version))
+}
+
+/// Reads the configuration of a package in the store.
+pub fn read_package_config(dir: &Path) -> Result<PackageConfig> {
+    let file = dir.join("dpt").join("pkg.kdl");
+    get_package_config(
+        &fs::read_to_string(&file)
+            .context(anyhow!("Failed to read {}", file.display()))?,
+    )
+}
+
 pub fn get_installed_packages_without_dpt_file() -> Result<Vec<OnlinePackage>> {
     let store = get_store_location();
     let entries = fs::read_dir(store)?;
@@ -77,3 +100,117 @@ pub fn get_installed_packages() -> Result<Vec<OnlinePackage>> {
         .map(|x| x.to_owned())
         .collect::<Vec<OnlinePackage>>())
 }
+
+/// Checks whether the dpt file asks for the immutable attribute to be set on
+/// the packages in the store, as of the last rebuild.
+pub fn store_is_immutable() -> Result<bool> {
+    if !get_dpt_lock_location().exists() {
+        return Ok(false);
+    }
+    Ok(read_dpt_lock_file()?.immutable_store)
+}
+
+/// Sets or clears the immutable attribute of a file or directory, which only
+/// root can do. Filesystems without attributes never have it, so clearing it
+/// on them does nothing.
+fn set_immutable(path: &Path, immutable: bool) -> io::Result<()> {
+    let file = File::open(path)?;
+    let mut flags: libc::c_int = 0;
+    if unsafe {
+        libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags)
+    } < 0
+    {
+        let e = io::Error::last_os_error();
+        if !immutable
+            && matches!(e.raw_os_error(), Some(libc::ENOTTY | libc::EOPNOTSUPP))
+        {
+            return Ok(());
+        }
+        return Err(e);
+    }
+    let new = match immutable {
+        true => flags | FS_IMMUTABLE_FL,
+        false => flags & !FS_IMMUTABLE_FL,
+    };
+    if new != flags
+        && unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &new) }
+            < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Makes the directory of a package in the store read only, by removing the
+/// write bits of everything in it and, when asked, setting the immutable
+/// attribute, so that even root can't change it without unlocking it first.
+pub fn lock_package_dir(dir: &Path, immutable: bool) -> Result<()> {
+    for ent in WalkDir::new(dir) {
+        let ent = ent?;
+        let kind = ent.file_type();
+        if !kind.is_file() && !kind.is_dir() {
+            continue;
+        }
+        // The mode of an immutable inode can't be changed
+        set_immutable(ent.path(), false)
+            .context(anyhow!("Failed to unlock {}", ent.path().display()))?;
+        let mode = ent.metadata()?.permissions().mode();
+        fs::set_permissions(ent.path(), Permissions::from_mode(mode & !0o222))?;
+        if immutable {
+            set_immutable(ent.path(), true).context(anyhow!(
+                "Failed to make {} immutable, does its filesystem support it?",
+                ent.path().display()
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// Undoes `lock_package_dir`, giving the owner write access back.
+pub fn unlock_package_dir(dir: &Path) -> Result<()> {
+    for ent in WalkDir::new(dir) {
+        let ent = ent?;
+        let kind = ent.file_type();
+        if !kind.is_file() && !kind.is_dir() {
+            continue;
+        }
+        set_immutable(ent.path(), false)
+            .context(anyhow!("Failed to unlock {}", ent.path().display()))?;
+        let mode = ent.metadata()?.permissions().mode();
+        fs::set_permissions(ent.path(), Permissions::from_mode(mode | 0o200))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_and_unlock() {
+        let dir = std::env::temp_dir()
+            .join(format!("dpt-store-{}", std::process::id()));
+        fs::create_dir_all(dir.join("usr/bin")).unwrap();
+        fs::write(dir.join("usr/bin/foo"), "foo").unwrap();
+        fs::set_permissions(
+            dir.join("usr/bin/foo"),
+            Permissions::from_mode(0o775),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("foo", dir.join("usr/bin/bar")).unwrap();
+        let mode = |x: &str| {
+            fs::metadata(dir.join(x)).unwrap().permissions().mode() & 0o7777
+        };
+
+        lock_package_dir(&dir, false).unwrap();
+        assert_eq!(mode("usr/bin/foo"), 0o555);
+        assert_eq!(mode("usr/bin") & 0o222, 0);
+        assert_eq!(mode("") & 0o222, 0);
+
+        unlock_package_dir(&dir).unwrap();
+        assert_eq!(mode("usr/bin/foo"), 0o755);
+        assert_eq!(mode("usr/bin") & 0o222, 0o200);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    ffi::CStr,
+    io::{self, ErrorKind},
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context, Result};
+use nix::{
+    fcntl::{open, OFlag},
+    sched::{unshare, CloneFlags},
+    sys::{
+        prctl,
+        stat::Mode,
+        wait::{waitpid, WaitStatus},
+    },
+    unistd::{close, fork, setresgid, setresuid, ForkResult, Gid, Uid},
+};
+
+/// Checks whether `uid` is allowed to create user namespaces, by trying it in
+/// a short lived child process.
+pub fn user_namespaces_available(uid: u32, gid: u32) -> bool {
+    // The child only makes raw system calls before exiting.
+    match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            let ok = drop_privileges(uid, gid).is_ok()
+                && unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS)
+                    .is_ok();
+            unsafe { nix::libc::_exit(if ok { 0 } else { 1 }) }
+        }
+        Ok(ForkResult::Parent { child }) => {
+            matches!(waitpid(child, None), Ok(WaitStatus::Exited(_, 0)))
+        }
+        Err(_) => false,
+    }
+}
+
+/// Permanently sets the real, effective and saved user and group ids.
+pub fn drop_privileges(uid: u32, gid: u32) -> nix::Result<()> {
+    let (uid, gid) = (Uid::from_raw(uid), Gid::from_raw(gid));
+    setresgid(gid, gid, gid)?;
+    setresuid(uid, uid, uid)?;
+    // Coming from a setuid binary leaves /proc/self owned by root, which
+    // would keep us from writing our own uid and gid maps.
+    prctl::set_dumpable(true)
+}
+
+/// The contents of the id maps of a user namespace where a user and group map
+/// to themselves, built before forking.
+#[derive(Debug, Clone)]
+pub struct IdMaps {
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+}
+
+impl IdMaps {
+    pub fn new(uid: u32, gid: u32) -> Self {
+        IdMaps {
+            uid_map: format!("{uid} {uid} 1").into_bytes(),
+            gid_map: format!("{gid} {gid} 1").into_bytes(),
+        }
+    }
+}
+
+/// Writes contents to an existing file with nothing but system calls, so
+/// that it can be done between fork and exec.
+fn write_file(path: &CStr, contents: &[u8]) -> io::Result<()> {
+    let fd = open(path, OFlag::O_WRONLY | OFlag::O_CLOEXEC, Mode::empty())?;
+    let res = unsafe {
+        nix::libc::write(fd, contents.as_ptr().cast(), contents.len())
+    };
+    let err = io::Error::last_os_error();
+    close(fd)?;
+    if res < 0 {
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Moves the calling process into a new user and mount namespace with the
+/// given id maps. The process keeps full capabilities inside of the
+/// namespace until it execs. Meant to be called between fork and exec.
+pub fn enter_user_namespace(maps: &IdMaps) -> io::Result<()> {
+    unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS)?;
+    write_file(c"/proc/self/setgroups", b"deny")?;
+    write_file(c"/proc/self/uid_map", &maps.uid_map)?;
+    write_file(c"/proc/self/gid_map", &maps.gid_map)?;
+    Ok(())
+}
+
+/// Gets a private directory owned by `uid` to assemble rootless environments
+/// in, since the normal run location is only writable by root.
+pub fn get_user_run_location(uid: u32) -> Result<PathBuf> {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(x) => PathBuf::from(x).join("dpt"),
+        None => std::env::temp_dir().join(format!("dpt-{}", uid)),
+    };
+    if let Err(e) = std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        if e.kind() != ErrorKind::AlreadyExists {
+            return Err(e).context(format!(
+                "Failed to create run directory {}",
+                dir.display()
+            ));
+        }
+    }
+    let meta = dir.symlink_metadata()?;
+    if !meta.is_dir() || meta.uid() != uid {
+        bail!("Run directory {} is not owned by you!", dir.display());
+    }
+    Ok(dir)
+}
@@ -0,0 +1,356 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    fs::File,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use kdl::{KdlDocument, KdlEntry, KdlNode};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::{
+    pkg::{parse_kdl, Package},
+    repo::get_kdl_string_prop,
+    store::{get_dpt_dir, get_package_dir},
+};
+
+/// What a path of a package is, as recorded in its manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestEntry {
+    File {
+        mode: u32,
+        size: u64,
+        /// The SHA-256 of the contents, in hex.
+        sha256: String,
+    },
+    Dir {
+        mode: u32,
+    },
+    Symlink {
+        target: PathBuf,
+    },
+}
+
+/// The files of a package, by their path relative to its directory.
+pub type Manifest = BTreeMap<PathBuf, ManifestEntry>;
+
+/// What is wrong with a path of a package in the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Problem {
+    /// Its contents, permissions or type changed.
+    Modified,
+    Missing,
+    /// It isn't in the manifest.
+    Extra,
+}
+
+/// A path of a package along with what is wrong with it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct FileProblem {
+    /// Relative to the directory of the package.
+    pub path: PathBuf,
+    pub problem: Problem,
+}
+
+/// The outcome of verifying a package, as printed by `dpt verify`.
+#[derive(Debug, Serialize)]
+pub struct Verification {
+    #[serde(flatten)]
+    pub package: Package,
+    /// Whether the package has a manifest to be verified against.
+    pub manifest: bool,
+    pub problems: Vec<FileProblem>,
+}
+
+impl Verification {
+    /// Whether nothing is wrong with the package. Packages without a
+    /// manifest can't be checked, so they count as ok.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = format!("{} {}", self.package.name, self.package.version);
+        if !self.manifest {
+            return write!(f, "{}: unverified (no manifest)", name);
+        }
+        if self.problems.is_empty() {
+            return write!(f, "{}: ok", name);
+        }
+        let problems = self
+            .problems
+            .iter()
+            .map(|x| {
+                let problem = match x.problem {
+                    Problem::Modified => "modified",
+                    Problem::Missing => "missing",
+                    Problem::Extra => "extra",
+                };
+                format!("{}: {} /{}", name, problem, x.path.display())
+            })
+            .collect::<Vec<String>>();
+        write!(f, "{}", problems.join("\n"))
+    }
+}
+
+/// Gets the file the manifest of pkg is kept in. Manifests are kept outside
+/// of the store, so that they don't end up in environments.
+pub fn get_manifest_location(pkg: &Package) -> PathBuf {
+    get_dpt_dir()
+        .join("manifests")
+        .join(format!("{}-{}.kdl", pkg.name, pkg.version))
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)
+        .context(anyhow!("Failed to read {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Builds the manifest of the files in dir, as they are now.
+pub fn build_manifest(dir: &Path) -> Result<Manifest> {
+    let mut manifest = Manifest::new();
+    for ent in WalkDir::new(dir).min_depth(1) {
+        let ent = ent?;
+        let meta = ent.path().symlink_metadata()?;
+        let mode = meta.permissions().mode() & 0o7777;
+        let entry = if meta.is_symlink() {
+            ManifestEntry::Symlink {
+                target: std::fs::read_link(ent.path())?,
+            }
+        } else if meta.is_dir() {
+            ManifestEntry::Dir { mode }
+        } else {
+            ManifestEntry::File {
+                mode,
+                size: meta.len(),
+                sha256: hash_file(ent.path())?,
+            }
+        };
+        manifest.insert(ent.path().strip_prefix(dir)?.to_path_buf(), entry);
+    }
+    Ok(manifest)
+}
+
+pub fn write_manifest(path: &Path, manifest: &Manifest) -> Result<()> {
+    let mut doc = KdlDocument::new();
+    for (file, entry) in manifest {
+        let file = file.to_string_lossy().to_string();
+        let node = match entry {
+            ManifestEntry::File { mode, size, sha256 } => {
+                let mut node = KdlNode::new("file");
+                node.push(KdlEntry::new(file));
+                node.push(KdlEntry::new_prop("mode", *mode as i128));
+                node.push(KdlEntry::new_prop("size", *size as i128));
+                node.push(KdlEntry::new_prop("sha256", sha256.clone()));
+                node
+            }
+            ManifestEntry::Dir { mode } => {
+                let mut node = KdlNode::new("dir");
+                node.push(KdlEntry::new(file));
+                node.push(KdlEntry::new_prop("mode", *mode as i128));
+                node
+            }
+            ManifestEntry::Symlink { target } => {
+                let mut node = KdlNode::new("symlink");
+                node.push(KdlEntry::new(file));
+                node.push(KdlEntry::new_prop(
+                    "target",
+                    target.to_string_lossy().to_string(),
+                ));
+                node
+            }
+        };
+        doc.nodes_mut().push(node);
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::DirBuilder::new().recursive(true).create(dir)?;
+    }
+    std::fs::write(path, doc.to_string())
+        .context(anyhow!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+pub fn read_manifest(path: &Path) -> Result<Manifest> {
+    let doc = parse_kdl(&std::fs::read_to_string(path)?)?;
+    let mut manifest = Manifest::new();
+    for node in doc.nodes() {
+        let file = node
+            .get(0)
+            .and_then(|x| x.as_string())
+            .ok_or(anyhow!("Manifest entry without a path!"))?;
+        let int = |name: &str| {
+            node.get(name).and_then(|x| x.as_integer()).ok_or(anyhow!(
+                "Manifest entry {} has no {}!",
+                file,
+                name
+            ))
+        };
+        let entry = match node.name().value() {
+            "file" => ManifestEntry::File {
+                mode: int("mode")?.try_into()?,
+                size: int("size")?.try_into()?,
+                sha256: get_kdl_string_prop("sha256", node)?,
+            },
+            "dir" => ManifestEntry::Dir {
+                mode: int("mode")?.try_into()?,
+            },
+            "symlink" => ManifestEntry::Symlink {
+                target: PathBuf::from(get_kdl_string_prop("target", node)?),
+            },
+            x => bail!("Unknown manifest entry {}!", x),
+        };
+        manifest.insert(PathBuf::from(file), entry);
+    }
+    Ok(manifest)
+}
+
+/// Records the manifest of pkg as it is in the store, right after
+/// installing it.
+pub fn record_manifest(pkg: &Package) -> Result<()> {
+    let manifest = build_manifest(&get_package_dir(pkg))?;
+    write_manifest(&get_manifest_location(pkg), &manifest)
+        .context(anyhow!("Failed to record the manifest of {}", pkg.name))
+}
+
+/// Checks whether two entries match, leaving out the write bits, so that
+/// files of the store being made read-only, or writable again, aren't taken
+/// for changes to the package.
+fn same_entry(a: &ManifestEntry, b: &ManifestEntry) -> bool {
+    let strip = |x: &ManifestEntry| match x.clone() {
+        ManifestEntry::File { mode, size, sha256 } => ManifestEntry::File {
+            mode: mode & !0o222,
+            size,
+            sha256,
+        },
+        ManifestEntry::Dir { mode } => ManifestEntry::Dir {
+            mode: mode & !0o222,
+        },
+        x => x,
+    };
+    strip(a) == strip(b)
+}
+
+/// Compares the manifest a package was installed with to the one it has now.
+fn compare_manifests(
+    recorded: &Manifest,
+    current: &Manifest,
+) -> Vec<FileProblem> {
+    let mut problems = Vec::<FileProblem>::new();
+    let mut add = |path: &Path, problem| {
+        problems.push(FileProblem {
+            path: path.to_path_buf(),
+            problem,
+        })
+    };
+    for (path, entry) in recorded {
+        match current.get(path) {
+            None => add(path, Problem::Missing),
+            Some(x) if !same_entry(x, entry) => add(path, Problem::Modified),
+            Some(_) => {}
+        }
+    }
+    for path in current.keys() {
+        if !recorded.contains_key(path) {
+            add(path, Problem::Extra);
+        }
+    }
+    problems.sort();
+    problems
+}
+
+/// Checks the files of pkg in the store against its manifest.
+pub fn verify_package(pkg: &Package) -> Result<Verification> {
+    let location = get_manifest_location(pkg);
+    if !location.exists() {
+        return Ok(Verification {
+            package: pkg.clone(),
+            manifest: false,
+            problems: vec![],
+        });
+    }
+    let recorded = read_manifest(&location)
+        .context(anyhow!("Failed to read {}", location.display()))?;
+    let current = build_manifest(&get_package_dir(pkg))?;
+    Ok(Verification {
+        package: pkg.clone(),
+        manifest: true,
+        problems: compare_manifests(&recorded, &current),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_manifest_is_unverified() {
+        let x = Verification {
+            package: Package::new("foo".into(), "1.0.0".into()),
+            manifest: false,
+            problems: Vec::new(),
+        };
+        assert!(x.is_ok());
+        assert_eq!(x.to_string(), "foo 1.0.0: unverified (no manifest)");
+    }
+
+    #[test]
+    fn manifest_problems() {
+        let dir = std::env::temp_dir()
+            .join(format!("dpt-verify-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("usr/bin")).unwrap();
+        std::fs::write(dir.join("usr/bin/foo"), "foo").unwrap();
+        std::fs::write(dir.join("usr/bin/bar"), "bar").unwrap();
+        std::os::unix::fs::symlink("foo", dir.join("usr/bin/baz")).unwrap();
+
+        let recorded = build_manifest(&dir).unwrap();
+        let file = dir.with_extension("kdl");
+        write_manifest(&file, &recorded).unwrap();
+        assert_eq!(read_manifest(&file).unwrap(), recorded);
+        assert!(compare_manifests(&recorded, &recorded).is_empty());
+
+        // Taking the write bits away doesn't count as a change
+        std::fs::set_permissions(
+            dir.join("usr/bin/bar"),
+            std::fs::Permissions::from_mode(0o444),
+        )
+        .unwrap();
+        let locked = build_manifest(&dir).unwrap();
+        assert!(compare_manifests(&recorded, &locked).is_empty());
+
+        // Same size, different contents
+        std::fs::write(dir.join("usr/bin/foo"), "oof").unwrap();
+        std::fs::remove_file(dir.join("usr/bin/bar")).unwrap();
+        std::fs::write(dir.join("usr/bin/qux"), "").unwrap();
+        std::fs::set_permissions(
+            dir.join("usr/bin"),
+            std::fs::Permissions::from_mode(0o700),
+        )
+        .unwrap();
+        let problems =
+            compare_manifests(&recorded, &build_manifest(&dir).unwrap())
+                .into_iter()
+                .map(|x| (x.path.to_string_lossy().to_string(), x.problem))
+                .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            [
+                ("usr/bin".to_string(), Problem::Modified),
+                ("usr/bin/bar".to_string(), Problem::Missing),
+                ("usr/bin/foo".to_string(), Problem::Modified),
+                ("usr/bin/qux".to_string(), Problem::Extra),
+            ]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_file(file).unwrap();
+    }
+}